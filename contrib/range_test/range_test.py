@@ -0,0 +1,96 @@
+"""Packet error rate of range-test frames, from `rtl_433` JSON output.
+
+    $ rtl_433 -s 2.5e6 -R 67 -f 433e6 -F json | python3 contrib/range_test/range_test.py
+
+Prints a line for every frame which makes it, and a summary per node on exit (Ctrl-C). Frames
+are counted from the first one received.
+"""
+
+import sys
+import json
+import struct
+
+MAGIC = b'RT'
+PAYLOAD_LEN = 6
+
+
+class Link:
+    def __init__(self, seq):
+        self.first = seq
+        self.last = seq
+        self.received = 1
+        self.gaps = []
+
+    def update(self, seq):
+        if seq < self.last:
+            # the node was restarted
+            print(f"sequence went back from {self.last} to {seq}, starting over", file=sys.stderr)
+            self.__init__(seq)
+            return
+        elif seq == self.last:
+            return
+
+        lost = seq - self.last - 1
+        if lost:
+            self.gaps.append((self.last + 1, lost))
+        self.last = seq
+        self.received += 1
+
+    @property
+    def expected(self):
+        return self.last - self.first + 1
+
+    @property
+    def per(self):
+        return 1 - self.received / self.expected
+
+
+def parse(line):
+    """Returns (node, sequence number) for range-test frames, `None` for anything else."""
+    try:
+        data = json.loads(line)
+    except ValueError:
+        return None
+
+    pl = bytes(data.get('payload', []))
+    if 'from' not in data or len(pl) < PAYLOAD_LEN or pl[:2] != MAGIC:
+        return None
+
+    (seq,) = struct.unpack('<I', pl[2:PAYLOAD_LEN])
+    return (data['from'], seq)
+
+
+def summary(links):
+    for (node, link) in sorted(links.items()):
+        print(f"node {node}: {link.received}/{link.expected} frames, PER {link.per:.1%}")
+        for (start, lost) in link.gaps:
+            print(f"  lost {lost} frame(s) from #{start}")
+        longest = max((lost for (_, lost) in link.gaps), default=0)
+        print(f"  {len(link.gaps)} gap(s), longest {longest}")
+
+
+def main():
+    links = {}
+
+    try:
+        for line in sys.stdin:
+            frame = parse(line)
+            if frame is None:
+                continue
+
+            (node, seq) = frame
+            if node in links:
+                links[node].update(seq)
+            else:
+                links[node] = Link(seq)
+
+            link = links[node]
+            print(f"node {node}: #{seq}, {link.received}/{link.expected}, PER {link.per:.1%}")
+    except KeyboardInterrupt:
+        pass
+
+    summary(links)
+
+
+if __name__ == '__main__':
+    main()
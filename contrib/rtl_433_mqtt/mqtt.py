@@ -1,6 +1,8 @@
 import re
 import sys
+import hmac
 import json
+import struct
 
 import click
 from paho.mqtt import client as mqtt
@@ -13,12 +15,40 @@ KLIMALOGG_MAP = {
 }
 
 
+# Nexus-TH sensor ID (the node's RadioHead address, with the `nexus` feature) -> room
+NEXUS_MAP = {}
+
+# RadioHead node address -> room
+RADIOHEAD_ROOMS = {}
+# for nodes which aren't in the map above
+RADIOHEAD_ROOM = 'living-room'
+
+# legacy mode (one packet per sensor)
 RADIOHEAD_MAP = {
-    237: ('living-room', 'temperature'),
-    238: ('living-room', 'humidity'),
-    239: ('living-room', 'co2')
+    237: 'temperature',
+    238: 'humidity',
+    239: 'co2'
 }
 
+PAYLOAD_VERSION = 1
+PAYLOAD_LEN = 9
+# same, followed by min/max/mean/count for every sensor
+PAYLOAD_VERSION_EXTENDED = 2
+SUMMARY_LEN = 7
+# first bytes of range-test payloads, see `src/range_test.rs`
+RANGE_TEST_MAGIC = b'RT'
+
+# RadioHead node address -> pre-shared key (16 bytes), for nodes which authenticate their packets
+RADIOHEAD_KEYS = {}
+FLAG_AUTHENTICATED = 0x08
+COUNTER_LEN = 4
+MAC_LEN = 4
+
+# last sequence number seen, per node (and packet ID, in legacy mode)
+last_seq = {}
+# last authentication counter seen, per node
+last_counter = {}
+
 
 def handle_klimalogg(data):
     m = re.match(r'^([\d\.]+) C$', data['temperature_C'])
@@ -28,9 +58,111 @@ def handle_klimalogg(data):
     yield (room, 'humidity', str(data['humidity']))
 
 
-def handle_radiohead(data):
+def handle_nexus(data):
+    m = re.match(r'^(-?[\d\.]+)( C)?$', str(data['temperature_C']))
+    room = NEXUS_MAP.get(data['id'], RADIOHEAD_ROOM)
+    yield (room, 'temperature', m.group(1))
+    if 'humidity' in data:
+        yield (room, 'humidity', str(data['humidity']))
+
+
+def siphash24(key, data):
+    mask = 0xffffffffffffffff
+
+    def rotl(x, b):
+        return ((x << b) | (x >> (64 - b))) & mask
+
+    def sipround(v):
+        v[0] = (v[0] + v[1]) & mask
+        v[1] = rotl(v[1], 13) ^ v[0]
+        v[0] = rotl(v[0], 32)
+        v[2] = (v[2] + v[3]) & mask
+        v[3] = rotl(v[3], 16) ^ v[2]
+        v[0] = (v[0] + v[3]) & mask
+        v[3] = rotl(v[3], 21) ^ v[0]
+        v[2] = (v[2] + v[1]) & mask
+        v[1] = rotl(v[1], 17) ^ v[2]
+        v[2] = rotl(v[2], 32)
+
+    def compress(v, m):
+        v[3] ^= m
+        sipround(v)
+        sipround(v)
+        v[0] ^= m
+
+    (k0, k1) = struct.unpack('<QQ', key)
+    v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573
+    ]
+
+    tail = len(data) % 8
+    for i in range(0, len(data) - tail, 8):
+        compress(v, int.from_bytes(data[i:i + 8], 'little'))
+    compress(v, int.from_bytes(data[len(data) - tail:], 'little') | ((len(data) & 0xff) << 56))
+
+    v[2] ^= 0xff
+    for _ in range(4):
+        sipround(v)
+    return v[0] ^ v[1] ^ v[2] ^ v[3]
+
+
+def verify(data, key):
+    """Check the MAC and counter of an authenticated packet, return the actual payload."""
+    pl = bytes(data['payload'])
+    if len(pl) < COUNTER_LEN + MAC_LEN:
+        return None
+
+    (payload, counter, mac) = (pl[:-8], pl[-8:-4], pl[-4:])
+    header = bytes([data['to'], data['from'], data['id'], data['flags']])
+    expected = siphash24(key, header + payload + counter).to_bytes(8, 'little')[:MAC_LEN]
+
+    if not hmac.compare_digest(mac, expected):
+        print(f"{data['from']}: wrong MAC", file=sys.stderr)
+        return None
+
+    counter = int.from_bytes(counter, 'little')
+    last = last_counter.get(data['from'], -1)
+    if counter == last:
+        # the same packet again, nodes may send them more than once
+        return None
+    elif counter < last:
+        print(f"{data['from']}: replayed packet", file=sys.stderr)
+        return None
+
+    last_counter[data['from']] = counter
+    return list(payload)
+
+
+def check_sequence(key, seq, modulo):
+    """Returns `False` for duplicates, complains about lost packets."""
+    last = last_seq.get(key)
+    last_seq[key] = seq
+
+    if last is None:
+        return True
+    elif seq == last:
+        return False
+
+    lost = (seq - last - 1) % modulo
+    if lost:
+        print(f"{key}: lost {lost} packet(s)", file=sys.stderr)
+    return True
+
+
+def handle_radiohead_legacy(data, room):
     pl = data['payload']
-    (room, measure) = RADIOHEAD_MAP[data['id']]
+    measure = RADIOHEAD_MAP.get(data['id'])
+    if measure is None:
+        print(f"{data['from']}: unknown packet id {data['id']}", file=sys.stderr)
+        return
+
+    # the sequence number is in the low 4 bits of the flags
+    if not check_sequence((data['from'], data['id']), data['flags'] & 0x0f, 16):
+        return
+
     val = pl[1] * 256 + pl[0]
 
     if measure == 'temperature':
@@ -39,6 +171,63 @@ def handle_radiohead(data):
     yield (room, measure, str(val))
 
 
+def handle_radiohead(data):
+    pl = data['payload']
+    room = RADIOHEAD_ROOMS.get(data['from'], RADIOHEAD_ROOM)
+
+    key = RADIOHEAD_KEYS.get(data['from'])
+    if key is not None:
+        if not data['flags'] & FLAG_AUTHENTICATED:
+            print(f"{data['from']}: packet isn't authenticated", file=sys.stderr)
+            return
+        pl = verify(data, key)
+        if pl is None:
+            return
+
+    if bytes(pl[:2]) == RANGE_TEST_MAGIC:
+        # range-test frames are for `contrib/range_test/range_test.py`
+        return
+
+    if len(pl) < PAYLOAD_LEN or pl[0] not in (PAYLOAD_VERSION, PAYLOAD_VERSION_EXTENDED):
+        yield from handle_radiohead_legacy(data, room)
+        return
+
+    (_version, seq, flags, temperature, humidity, co2) = struct.unpack('<BBBhHH', bytes(pl[:PAYLOAD_LEN]))
+
+    if not check_sequence(data['from'], seq, 256):
+        return
+
+    if flags & 0x01:
+        yield (room, 'temperature', str(temperature / 100))
+    if flags & 0x02:
+        yield (room, 'humidity', str(humidity))
+    if flags & 0x04:
+        yield (room, 'co2', str(co2))
+        yield (room, 'co2/status', 'ok')
+    elif flags & 0x08:
+        yield (room, 'co2/status', 'warming')
+
+    if pl[0] == PAYLOAD_VERSION_EXTENDED:
+        yield from handle_summaries(room, pl[PAYLOAD_LEN:])
+
+
+def handle_summaries(room, pl):
+    """Publish min/max/mean/count as JSON attributes, e.g. for `json_attributes_topic`."""
+    for (n, (measure, fmt, convert)) in enumerate((('temperature', '<hhhB', lambda v: v / 100),
+                                                   ('humidity', '<HHHB', int),
+                                                   ('co2', '<HHHB', int))):
+        (minimum, maximum, mean, count) = struct.unpack(
+            fmt, bytes(pl[n * SUMMARY_LEN:(n + 1) * SUMMARY_LEN]))
+        if count == 0:
+            continue
+        yield (room, f'{measure}/attributes', json.dumps({
+            'min': convert(minimum),
+            'max': convert(maximum),
+            'mean': convert(mean),
+            'count': count
+        }))
+
+
 def iter_stdin():
     for line in sys.stdin:
         data = json.loads(line)
@@ -48,6 +237,8 @@ def iter_stdin():
         
         if model.startswith('Klima'):
             yield from handle_klimalogg(data)
+        elif model.startswith('Nexus'):
+            yield from handle_nexus(data)
         else:
             yield from handle_radiohead(data)
 
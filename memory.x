@@ -3,7 +3,8 @@ MEMORY
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
   /* TODO Adjust these memory regions to match your device memory layout */
   /* These values correspond to the LM3S6965, one of the few devices QEMU can emulate */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  /* the last 128K sector is left out, it's used by nvm.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 384K
   RAM : ORIGIN = 0x20000000, LENGTH = 96K
 }
 
@@ -0,0 +1,258 @@
+// Duty-cycle accounting. The 433 MHz SRD band (ETSI EN 300 220) limits how much of every hour a
+// node may spend transmitting, so every frame is checked against a sliding one-hour window (in
+// one-minute slots) before it's sent, refused if it doesn't fit, and charged once it's queued.
+
+use core::cmp;
+
+use crate::rf::{Error, Header, OokFrame, RfEncoder};
+
+const SLOT_LEN_S: u32 = 60;
+const NUM_SLOTS: u32 = 60;
+const WINDOW_US: u64 = (SLOT_LEN_S * NUM_SLOTS) as u64 * 1_000_000;
+
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    /// frames sent since reset
+    pub frames: u32,
+    /// airtime since reset, in ms
+    pub total_ms: u32,
+    /// transmissions which were put off to stay within the budget
+    pub deferred: u32,
+    /// frames which were refused
+    pub dropped: u32,
+}
+
+pub struct AirtimeBudget {
+    limit_us: u32,
+    slots: [u32; NUM_SLOTS as usize],
+    // index of the current slot, since reset
+    slot: u32,
+    // sub-ms remainder of `stats.total_ms`, in us
+    total_us: u32,
+    pub stats: Stats,
+}
+
+impl AirtimeBudget {
+    /// `duty_cycle` is in 1/1000ths, e.g. 10 for 1%.
+    pub fn new(duty_cycle: u16) -> Self {
+        Self {
+            limit_us: (WINDOW_US * duty_cycle as u64 / 1000) as u32,
+            slots: [0; NUM_SLOTS as usize],
+            slot: 0,
+            total_us: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Forget about whatever is now older than an hour.
+    pub fn advance(&mut self, now_s: u32) {
+        let slot = now_s / SLOT_LEN_S;
+        let elapsed = slot.wrapping_sub(self.slot);
+        for n in 1..=cmp::min(elapsed, NUM_SLOTS) {
+            self.slots[((self.slot + n) % NUM_SLOTS) as usize] = 0;
+        }
+        self.slot = slot;
+    }
+
+    pub fn limit_us(&self) -> u32 {
+        self.limit_us
+    }
+
+    /// Airtime spent in the last hour, in us.
+    pub fn used_us(&self) -> u32 {
+        self.slots.iter().sum()
+    }
+
+    pub fn available_us(&self) -> u32 {
+        self.limit_us.saturating_sub(self.used_us())
+    }
+
+    /// Whether there's enough left for `airtime_us`. Frames which don't fit count as dropped.
+    pub fn check(&mut self, now_s: u32, airtime_us: u32) -> bool {
+        self.advance(now_s);
+
+        if airtime_us > self.available_us() {
+            self.stats.dropped += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Charge `airtime_us` to the budget, for a frame which went out (after `check`).
+    pub fn spend(&mut self, airtime_us: u32) {
+        self.slots[(self.slot % NUM_SLOTS) as usize] += airtime_us;
+        self.stats.frames += 1;
+        self.total_us += airtime_us;
+        self.stats.total_ms += self.total_us / 1000;
+        self.total_us %= 1000;
+    }
+
+    pub fn defer(&mut self) {
+        self.stats.deferred += 1;
+    }
+}
+
+/// Passes frames on to `radio` only if they fit in `budget`.
+pub struct Metered<'t, R> {
+    radio: &'t mut R,
+    budget: &'t mut AirtimeBudget,
+    now_s: u32,
+    spent_us: u32,
+}
+
+impl<'t, R> Metered<'t, R>
+where
+    R: RfEncoder,
+    R::Error: From<Error>,
+{
+    pub fn new(radio: &'t mut R, budget: &'t mut AirtimeBudget, now_s: u32) -> Self {
+        Self {
+            radio,
+            budget,
+            now_s,
+            spent_us: 0,
+        }
+    }
+
+    /// Airtime of the frames which went through so far, in us.
+    pub fn spent_us(&self) -> u32 {
+        self.spent_us
+    }
+}
+
+impl<'t, R> RfEncoder for Metered<'t, R>
+where
+    R: RfEncoder,
+    R::Error: From<Error>,
+{
+    type Error = R::Error;
+
+    fn encode(&self, header: &Header, payload: &[u8]) -> Result<OokFrame, R::Error> {
+        self.radio.encode(header, payload)
+    }
+
+    fn send_raw(&mut self, frame: OokFrame) -> Result<(), R::Error> {
+        let airtime_us = frame.airtime_us();
+        if !self.budget.check(self.now_s, airtime_us) {
+            return Err(Error::DutyCycleExceeded.into());
+        }
+
+        // frames the radio refuses don't use up any airtime
+        self.radio.send_raw(frame)?;
+        self.budget.spend(airtime_us);
+        self.spent_us += airtime_us;
+        Ok(())
+    }
+
+    fn is_busy(&self) -> bool {
+        self.radio.is_busy()
+    }
+
+    fn airtime_us(&self, payload_len: usize) -> u32 {
+        self.radio.airtime_us(payload_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{radiohead_ask::Config, rf::MockEncoder};
+
+    const HEADER: Header = Header {
+        from: 1,
+        to: 2,
+        id: 3,
+        flags: 0,
+    };
+
+    // counts the frames it gets, or refuses them all when `jammed`
+    struct Radio {
+        encoder: MockEncoder,
+        frames: usize,
+        jammed: bool,
+    }
+
+    impl Radio {
+        fn new(jammed: bool) -> Self {
+            Self {
+                encoder: MockEncoder::new(Config::default()),
+                frames: 0,
+                jammed,
+            }
+        }
+    }
+
+    impl RfEncoder for Radio {
+        type Error = Error;
+
+        fn encode(&self, header: &Header, payload: &[u8]) -> Result<OokFrame, Error> {
+            self.encoder.encode(header, payload)
+        }
+
+        fn send_raw(&mut self, _: OokFrame) -> Result<(), Error> {
+            if self.jammed {
+                return Err(Error::Busy);
+            }
+            self.frames += 1;
+            Ok(())
+        }
+
+        fn is_busy(&self) -> bool {
+            self.jammed
+        }
+
+        fn airtime_us(&self, payload_len: usize) -> u32 {
+            self.encoder.airtime_us(payload_len)
+        }
+    }
+
+    #[test]
+    fn frames_are_charged() {
+        let mut radio = Radio::new(false);
+        let mut budget = AirtimeBudget::new(10);
+        let frame_us = radio.airtime_us(20);
+        let mut metered = Metered::new(&mut radio, &mut budget, 0);
+
+        metered.send_frame(&HEADER, &[0; 20]).unwrap();
+        metered.send_frame(&HEADER, &[0; 20]).unwrap();
+        assert_eq!(metered.spent_us(), frame_us * 2);
+        assert_eq!(budget.used_us(), frame_us * 2);
+        assert_eq!(budget.stats.frames, 2);
+        assert_eq!(radio.frames, 2);
+    }
+
+    #[test]
+    fn frames_over_the_budget_are_refused() {
+        let mut radio = Radio::new(false);
+        // 36 s per hour
+        let mut budget = AirtimeBudget::new(10);
+        let fit = (budget.limit_us() / radio.airtime_us(60)) as usize;
+
+        let mut metered = Metered::new(&mut radio, &mut budget, 0);
+        for _ in 0..fit {
+            metered.send_frame(&HEADER, &[0; 60]).unwrap();
+        }
+        assert_eq!(
+            metered.send_frame(&HEADER, &[0; 60]),
+            Err(Error::DutyCycleExceeded)
+        );
+        assert_eq!(radio.frames, fit);
+        assert_eq!(budget.stats.dropped, 1);
+
+        // an hour later, it's all available again
+        budget.advance(3600);
+        assert_eq!(budget.available_us(), budget.limit_us());
+    }
+
+    #[test]
+    fn refused_frames_are_not_charged() {
+        let mut radio = Radio::new(true);
+        let mut budget = AirtimeBudget::new(10);
+        let mut metered = Metered::new(&mut radio, &mut budget, 0);
+
+        assert_eq!(metered.send_frame(&HEADER, &[0; 20]), Err(Error::Busy));
+        assert_eq!(metered.spent_us(), 0);
+        assert_eq!(budget.used_us(), 0);
+        assert_eq!(budget.stats.frames, 0);
+    }
+}
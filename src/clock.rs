@@ -0,0 +1,13 @@
+// Time for the sensor drivers' timeouts, which don't know about the system tick.
+
+/// Time since some point in the past, for timeouts.
+pub trait Clock {
+    /// in ms, wrapping around
+    fn now_ms(&self) -> u32;
+
+    /// How far `now_ms` jumps at a time, in ms. Delays are made that much longer, so that
+    /// they're never cut short.
+    fn resolution_ms(&self) -> u32 {
+        1
+    }
+}
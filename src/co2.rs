@@ -0,0 +1,276 @@
+// CO2 sensors, as seen by the main loop: a reading is asked for, then polled for until it's in.
+// The MH-Z19B is used unless the `scd4x`, `scd30` or `senseair-s8` feature picks another one.
+
+use core::cmp;
+#[cfg(any(test, feature = "scd4x", feature = "scd30"))]
+use embedded_hal::blocking::i2c;
+#[cfg(any(test, not(any(feature = "scd4x", feature = "scd30"))))]
+use embedded_hal::serial;
+
+use crate::clock::Clock;
+#[cfg(any(
+    test,
+    not(any(feature = "scd4x", feature = "scd30", feature = "senseair-s8"))
+))]
+use crate::mhz19b::{self, Mhz19b};
+#[cfg(any(test, feature = "scd4x", feature = "scd30"))]
+use crate::sensirion::{self, Scd};
+#[cfg(any(test, feature = "senseair-s8"))]
+use crate::{
+    modbus,
+    senseair::{self, S8},
+};
+
+// nothing indoors goes below outdoor levels, which are above this
+const MIN_PLAUSIBLE_PPM: u16 = 300;
+
+// how long the MH-Z19B and the S8 need to warm up after power-on
+#[cfg(any(
+    test,
+    not(any(feature = "scd4x", feature = "scd30", feature = "senseair-s8"))
+))]
+const MHZ19B_WARMUP_S: u32 = 180;
+#[cfg(any(test, feature = "senseair-s8"))]
+const S8_WARMUP_S: u32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Ready,
+    /// still preheating, the CO2 readings don't mean much yet
+    WarmingUp,
+    /// running outside its operating range, or reporting an error
+    Degraded,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub co2_ppm: u16,
+    /// in 0.01 C, inside the sensor (the MH-Z19B only has whole degrees), for sensors which
+    /// measure it
+    pub temperature: Option<i16>,
+    /// in %, for sensors which measure it
+    pub humidity: Option<u16>,
+    pub condition: Condition,
+}
+
+pub trait Co2Sensor {
+    type Error;
+
+    /// Settings applied on boot. Automatic self-calibration takes the lowest level seen over a
+    /// day or so as 400 ppm.
+    fn init(&mut self, self_calibration: bool) -> Result<(), Self::Error>;
+
+    /// Ask for a reading. A request which is still pending may either start over or carry on.
+    fn start_request(&mut self) -> Result<(), Self::Error>;
+
+    /// `WouldBlock` until the reading is in (and when nothing was requested).
+    fn poll(&mut self) -> nb::Result<Reading, Self::Error>;
+
+    /// Top of the range. Readings there can't be trusted, it's where sensors end up when
+    /// something is wrong.
+    fn max_ppm(&self) -> u16;
+
+    /// How long readings mean nothing for after power-on, in s.
+    fn warmup_s(&self) -> u32;
+}
+
+#[cfg(any(
+    test,
+    not(any(feature = "scd4x", feature = "scd30", feature = "senseair-s8"))
+))]
+impl<S, C> Co2Sensor for Mhz19b<S, C>
+where
+    S: serial::Read<u8> + serial::Write<u8>,
+    C: Clock,
+{
+    type Error = mhz19b::Error;
+
+    fn init(&mut self, self_calibration: bool) -> Result<(), mhz19b::Error> {
+        self.set_abc(self_calibration)?;
+        self.set_detection_range(self.range())
+    }
+
+    fn start_request(&mut self) -> Result<(), mhz19b::Error> {
+        Mhz19b::start_request(self)
+    }
+
+    fn poll(&mut self) -> nb::Result<Reading, mhz19b::Error> {
+        let reading = Mhz19b::poll(self)?;
+        Ok(Reading {
+            co2_ppm: reading.co2_ppm,
+            temperature: Some(reading.temperature_c * 100),
+            humidity: None,
+            condition: match reading.condition() {
+                mhz19b::Condition::Ready => Condition::Ready,
+                mhz19b::Condition::WarmingUp => Condition::WarmingUp,
+                mhz19b::Condition::Degraded => Condition::Degraded,
+            },
+        })
+    }
+
+    fn max_ppm(&self) -> u16 {
+        self.range().ppm()
+    }
+
+    fn warmup_s(&self) -> u32 {
+        MHZ19B_WARMUP_S
+    }
+}
+
+#[cfg(any(test, feature = "scd4x", feature = "scd30"))]
+impl<I, C, E> Co2Sensor for Scd<I, C>
+where
+    I: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    C: Clock,
+{
+    type Error = sensirion::Error<E>;
+
+    fn init(&mut self, self_calibration: bool) -> Result<(), Self::Error> {
+        Scd::init(self, self_calibration)
+    }
+
+    fn start_request(&mut self) -> Result<(), Self::Error> {
+        Scd::start_request(self);
+        Ok(())
+    }
+
+    fn poll(&mut self) -> nb::Result<Reading, Self::Error> {
+        let measurement = Scd::poll(self)?;
+        Ok(Reading {
+            co2_ppm: measurement.co2_ppm,
+            temperature: Some(measurement.temperature),
+            humidity: Some(measurement.humidity),
+            condition: Condition::Ready,
+        })
+    }
+
+    fn max_ppm(&self) -> u16 {
+        sensirion::MAX_PPM
+    }
+
+    fn warmup_s(&self) -> u32 {
+        0
+    }
+}
+
+#[cfg(any(test, feature = "senseair-s8"))]
+impl<S, C> Co2Sensor for S8<S, C>
+where
+    S: serial::Read<u8> + serial::Write<u8>,
+    C: Clock,
+{
+    type Error = modbus::Error;
+
+    fn init(&mut self, self_calibration: bool) -> Result<(), modbus::Error> {
+        self.set_abc(self_calibration)
+    }
+
+    fn start_request(&mut self) -> Result<(), modbus::Error> {
+        S8::start_request(self)
+    }
+
+    fn poll(&mut self) -> nb::Result<Reading, modbus::Error> {
+        let reading = S8::poll(self)?;
+        Ok(Reading {
+            co2_ppm: reading.co2_ppm,
+            temperature: None,
+            humidity: None,
+            condition: if reading.status == 0 {
+                Condition::Ready
+            } else {
+                Condition::Degraded
+            },
+        })
+    }
+
+    fn max_ppm(&self) -> u16 {
+        senseair::MAX_PPM
+    }
+
+    fn warmup_s(&self) -> u32 {
+        S8_WARMUP_S
+    }
+}
+
+/// Rejects readings which can't be right: outside of what's possible indoors, at the top of
+/// the sensor's range, or changing faster than `max_rate` (ppm/s) since the last one which was
+/// accepted.
+pub struct PlausibilityFilter {
+    max_ppm: u16,
+    max_rate: u16,
+    // last accepted reading, and when it was taken (in s)
+    last: Option<(u16, u32)>,
+    pub rejected: u32,
+}
+
+impl PlausibilityFilter {
+    pub fn new(max_ppm: u16, max_rate: u16) -> Self {
+        Self {
+            max_ppm,
+            max_rate,
+            last: None,
+            rejected: 0,
+        }
+    }
+
+    pub fn accept(&mut self, co2_ppm: u16, now_s: u32) -> bool {
+        let in_range = co2_ppm >= MIN_PLAUSIBLE_PPM && co2_ppm < self.max_ppm;
+        // the longer it has been, the further it may have gone
+        let in_reach = match self.last {
+            Some((last, then)) => {
+                let elapsed_s = cmp::max(now_s.wrapping_sub(then), 1);
+                let change = (co2_ppm as i32 - last as i32).unsigned_abs();
+                change <= elapsed_s.saturating_mul(self.max_rate as u32)
+            }
+            None => true,
+        };
+
+        if in_range && in_reach {
+            self.last = Some((co2_ppm, now_s));
+            true
+        } else {
+            self.rejected += 1;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_out_of_range() {
+        let mut filter = PlausibilityFilter::new(5000, 10);
+        assert!(!filter.accept(MIN_PLAUSIBLE_PPM - 1, 0));
+        // the top of the range is where sensors end up when something is wrong
+        assert!(!filter.accept(5000, 10));
+        assert!(filter.accept(MIN_PLAUSIBLE_PPM, 20));
+        assert!(filter.accept(4999, 1000));
+        assert_eq!(filter.rejected, 2);
+    }
+
+    #[test]
+    fn rejects_jumps() {
+        let mut filter = PlausibilityFilter::new(5000, 10);
+        assert!(filter.accept(600, 100));
+        assert!(!filter.accept(900, 110));
+        // it's compared with the last accepted reading, not the rejected one
+        assert!(filter.accept(700, 110));
+        // readings in the same second may be as far apart as a second's worth
+        assert!(!filter.accept(711, 110));
+        assert!(filter.accept(710, 110));
+        assert_eq!(filter.rejected, 2);
+    }
+
+    #[test]
+    fn accepts_jumps_given_time() {
+        let mut filter = PlausibilityFilter::new(5000, 10);
+        assert!(filter.accept(600, u32::MAX - 9));
+        assert!(!filter.accept(900, 10));
+        // 30 s after the last accepted one, across the wrap-around
+        assert!(filter.accept(900, 20));
+        assert!(filter.accept(600, 50));
+        assert_eq!(filter.rejected, 1);
+    }
+}
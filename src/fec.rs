@@ -0,0 +1,143 @@
+// Forward error correction for RadioHead ASK frames (see `radiohead_ask::Config::fec`).
+//
+// Every nibble becomes an extended Hamming(8,4) codeword, which corrects one flipped bit and
+// detects two. Codewords are interleaved in blocks of 8 (4 bytes of data): the block is seen as
+// an 8x8 bit matrix and transposed, so a whole corrupted byte on the air (which is what a bad
+// 4b6b symbol turns into) costs every codeword in the block a single bit at most.
+
+/// Bytes of data per block
+pub const BLOCK_LEN: usize = 4;
+/// Bytes on the air per block
+pub const CODED_BLOCK_LEN: usize = 8;
+
+/// Bytes on the air for `len` bytes of data (the last block is padded).
+pub const fn coded_len(len: usize) -> usize {
+    len.div_ceil(BLOCK_LEN) * CODED_BLOCK_LEN
+}
+
+// bit n is position n of the Hamming code, bit 0 is the overall parity:
+//  0: p0, 1: p1, 2: p2, 3: d0, 4: p3, 5: d1, 6: d2, 7: d3
+fn hamming_encode(nibble: u8) -> u8 {
+    let d = |n: u8| (nibble >> n) & 1;
+    let p1 = d(0) ^ d(1) ^ d(3);
+    let p2 = d(0) ^ d(2) ^ d(3);
+    let p3 = d(1) ^ d(2) ^ d(3);
+
+    let codeword = p1 << 1 | p2 << 2 | d(0) << 3 | p3 << 4 | d(1) << 5 | d(2) << 6 | d(3) << 7;
+    codeword | (codeword.count_ones() as u8 & 1)
+}
+
+/// Returns the nibble and whether a bit had to be corrected, or `None` if there were two.
+fn hamming_decode(codeword: u8) -> Option<(u8, bool)> {
+    let syndrome = (1..8)
+        .filter(|n| codeword & (1 << n) > 0)
+        .fold(0, |s, n| s ^ n);
+    let parity_error = codeword.count_ones() & 1 > 0;
+
+    let (codeword, corrected) = match (syndrome, parity_error) {
+        (0, false) => (codeword, false),
+        // a single error, which may be in p0 itself (syndrome 0)
+        (n, true) => (codeword ^ (1 << n), true),
+        (_, false) => return None,
+    };
+
+    let b = |n: u8| (codeword >> n) & 1;
+    Some((b(3) | b(5) << 1 | b(6) << 2 | b(7) << 3, corrected))
+}
+
+// it's its own inverse
+fn transpose(block: &[u8; CODED_BLOCK_LEN]) -> [u8; CODED_BLOCK_LEN] {
+    let mut out = [0_u8; CODED_BLOCK_LEN];
+    for (i, o) in out.iter_mut().enumerate() {
+        for (j, b) in block.iter().enumerate() {
+            *o |= ((b >> i) & 1) << j;
+        }
+    }
+    out
+}
+
+/// `data` is padded with zeros if it's short.
+pub fn encode_block(data: &[u8]) -> [u8; CODED_BLOCK_LEN] {
+    let mut codewords = [0_u8; CODED_BLOCK_LEN];
+    for (i, b) in data.iter().take(BLOCK_LEN).enumerate() {
+        codewords[2 * i] = hamming_encode(b >> 4);
+        codewords[2 * i + 1] = hamming_encode(b & 0xf);
+    }
+    transpose(&codewords)
+}
+
+/// Returns the data and the number of bits which were corrected, or `None` if
+/// there were too many errors.
+pub fn decode_block(coded: &[u8; CODED_BLOCK_LEN]) -> Option<([u8; BLOCK_LEN], u8)> {
+    let codewords = transpose(coded);
+    let mut data = [0_u8; BLOCK_LEN];
+    let mut corrected = 0;
+
+    for (i, d) in data.iter_mut().enumerate() {
+        let (high, c_high) = hamming_decode(codewords[2 * i])?;
+        let (low, c_low) = hamming_decode(codewords[2 * i + 1])?;
+        *d = high << 4 | low;
+        corrected += c_high as u8 + c_low as u8;
+    }
+    Some((data, corrected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: [u8; BLOCK_LEN] = [0x12, 0xab, 0x00, 0xff];
+
+    #[test]
+    fn single_bit_errors_are_corrected() {
+        for nibble in 0..16 {
+            let codeword = hamming_encode(nibble);
+            assert_eq!(hamming_decode(codeword), Some((nibble, false)));
+
+            for n in 0..8 {
+                assert_eq!(hamming_decode(codeword ^ (1 << n)), Some((nibble, true)));
+            }
+        }
+    }
+
+    #[test]
+    fn double_bit_errors_are_detected() {
+        for nibble in 0..16 {
+            let codeword = hamming_encode(nibble);
+
+            for n in 0..8 {
+                for m in n + 1..8 {
+                    assert_eq!(hamming_decode(codeword ^ (1 << n) ^ (1 << m)), None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bursts_are_recovered() {
+        let coded = encode_block(&DATA);
+        assert_eq!(decode_block(&coded), Some((DATA, 0)));
+
+        // up to 8 consecutive bits on the air cost every codeword a single bit at most
+        for start in 0..=(CODED_BLOCK_LEN - 1) * 8 {
+            for len in 1..=8 {
+                let mut corrupted = coded;
+                for bit in start..start + len {
+                    corrupted[bit / 8] ^= 1 << (bit % 8);
+                }
+
+                let (data, corrected) = decode_block(&corrupted).unwrap();
+                assert_eq!(data, DATA);
+                assert_eq!(corrected as usize, len);
+            }
+        }
+    }
+
+    #[test]
+    fn short_blocks_are_padded() {
+        assert_eq!(coded_len(0), 0);
+        assert_eq!(coded_len(5), 2 * CODED_BLOCK_LEN);
+        let (data, _) = decode_block(&encode_block(&DATA[..3])).unwrap();
+        assert_eq!(data, [0x12, 0xab, 0x00, 0x00]);
+    }
+}
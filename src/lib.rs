@@ -0,0 +1,31 @@
+//! The parts of the firmware which don't touch the hardware: protocols, encodings and sensor
+//! drivers, written against `embedded-hal` traits. They build for the host too, so that they
+//! can be tested there (`make test`).
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod airtime;
+pub mod auth;
+pub mod clock;
+pub mod co2;
+pub mod fec;
+// the CO2 sensor drivers are only built for the sensor which is used (and for the tests)
+#[cfg(any(
+    test,
+    not(any(feature = "scd4x", feature = "scd30", feature = "senseair-s8"))
+))]
+pub mod mhz19b;
+#[cfg(any(test, feature = "senseair-s8"))]
+pub mod modbus;
+pub mod nexus;
+pub mod payload;
+pub mod pin;
+pub mod radiohead_ask;
+pub mod range_test;
+pub mod rf;
+pub mod rng;
+#[cfg(any(test, feature = "senseair-s8"))]
+pub mod senseair;
+#[cfg(any(test, feature = "scd4x", feature = "scd30"))]
+pub mod sensirion;
+pub mod wire;
@@ -4,24 +4,23 @@
 #[macro_use]
 extern crate lazy_static;
 
-#[cfg(debug_assertions)]
-extern crate panic_itm;
-
-#[cfg(not(debug_assertions))]
-extern crate panic_halt;
-
 use cmim::{Context, Move};
 use core::{
     cell::{Cell, RefCell},
-    cmp, mem, slice,
+    cmp,
+    convert::TryFrom,
+    mem,
+    panic::PanicInfo,
+    sync::atomic::{self, Ordering},
 };
 use cortex_m::{
     interrupt::{free, Mutex},
     iprintln,
 };
 use cortex_m_rt::entry;
-use heapless::{consts::*, HistoryBuffer, Vec};
+use heapless::{consts::*, HistoryBuffer, String};
 use shared_bus::BusManagerSimple;
+use ssd1306::prelude::WriteOnlyDataCommand;
 use stm32f4xx_hal::{
     delay::Delay,
     i2c::I2c,
@@ -30,23 +29,99 @@ use stm32f4xx_hal::{
     stm32::{self, NVIC},
     timer::{Event, Timer},
 };
+use ufmt::uwrite;
+
+use clima_sensors::{airtime, auth, co2, nexus, payload, radiohead_ask, range_test, rf, rng, wire};
+use co2::Co2Sensor;
+use rf::RfEncoder;
 
+const TICKS_PER_SECOND: u32 = 5;
 const TICKS_UNTIL_RADIO_TX: u32 = 50; // 10 s
 const TICKS_UNTIL_SENSOR_READ: u32 = 10; // 2s
 
-mod mhz19b;
+// the TX interval is randomly made up to this much shorter/longer every time, so that we
+// don't keep stepping on other 433 MHz sensors (must be less than TICKS_UNTIL_RADIO_TX)
+const TX_JITTER_TICKS: u32 = 10; // 2 s
+
+// report-by-exception: send as soon as an average moves at least this much away from the
+// last value sent (temperature in 0.01 C, humidity in %, CO2 in ppm), and otherwise only every
+// TICKS_UNTIL_HEARTBEAT. `None` sends every TICKS_UNTIL_RADIO_TX
+const REPORT_THRESHOLDS: Option<Averages> = None;
+const TICKS_UNTIL_HEARTBEAT: u32 = 1500; // 5 min
+
+// extra copies of every frame, sent after a random gap (within TX_REPEAT_GAP_MS)
+const TX_REPEATS: u8 = 0;
+const TX_REPEAT_GAP_MS: (u16, u16) = (20, 100);
+
+// with `legacy-payload`, the packets are this far apart (they used to be sent one by one)
+const LEGACY_PACKET_GAP_MS: u32 = 100;
+
+// one frame per second at most in range-test mode; they're spaced out further to stay within
+// DUTY_CYCLE (every 12 s at 1%, or 24 s with `fec`), so that no frame is ever held back
+const TICKS_UNTIL_RANGE_TEST_TX: u32 = 5;
+
+// transmit a test signal for this long (in ms, at most radiohead_ask::MAX_TEST_DURATION_MS, and
+// cut short to what's left of the duty cycle) after booting, to tune the antenna,
+// e.g. Some((TestSignal::SquareWave(rf::Hertz(1000)), 30_000))
+const ANTENNA_TEST: Option<(radiohead_ask::TestSignal, u32)> = None;
+
+// RadioHead address of this node, derived from the MCU's unique ID if `None`
+const NODE_ADDRESS: Option<u8> = None;
+const DESTINATION_ADDRESS: u8 = node::BROADCAST;
+
+// Nexus channel (0-2), with the `nexus` feature
+const NEXUS_CHANNEL: u8 = 0;
+
+// maximum share of every hour spent transmitting, in 1/1000ths
+// (1% is the limit for most of the 433 MHz band, as per ETSI EN 300 220)
+const DUTY_CYCLE: u16 = 10;
+
+// CO2 sensor settings, applied on every boot. Automatic baseline correction (self-calibration)
+// should be off for rooms which never get fresh air, as it would take their lowest level as
+// 400 ppm. The detection range is only for the MH-Z19B
+const CO2_ABC: bool = true;
+#[cfg(not(any(feature = "scd4x", feature = "scd30", feature = "senseair-s8")))]
+const CO2_DETECTION_RANGE: clima_sensors::mhz19b::DetectionRange =
+    clima_sensors::mhz19b::DetectionRange::Ppm5000;
+
+// CO2 readings are ignored while the sensor warms up (about 3 min for the MH-Z19B). After that,
+// they're dropped when they change faster than this (ppm/s)
+const CO2_MAX_RATE: u16 = 25;
+
+// the CO2 sensor's own temperature sensor is used to check the MLX90614's; they're taken to
+// disagree past this (in 0.01 C), leaving room for the sensor warming itself up
+const TEMPERATURE_CROSS_CHECK: i16 = 500;
+
+// pre-shared key used to authenticate packets (see `auth`), `None` sends them as they are
+const NODE_KEY: Option<auth::Key> = None;
+
+#[cfg(any(
+    all(feature = "scd4x", feature = "scd30"),
+    all(feature = "scd4x", feature = "senseair-s8"),
+    all(feature = "scd30", feature = "senseair-s8")
+))]
+compile_error!("only one of the `scd4x`, `scd30` and `senseair-s8` features can be enabled");
+
+mod node;
+mod nvm;
 mod peripherals;
-mod radiohead_ask;
 mod ringbuffer;
 mod syscalls;
 mod ui;
 
+#[derive(Clone, Copy)]
 pub struct Averages {
     pub temperature: i16,
     pub humidity: u16,
     pub co2: u16,
 }
 
+impl Default for Averages {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Averages {
     pub fn new() -> Self {
         Self {
@@ -57,12 +132,85 @@ impl Averages {
     }
 }
 
+/// Readings taken since the last transmission.
+#[derive(Clone, Copy)]
+pub struct Interval<T> {
+    min: T,
+    max: T,
+    sum: i32,
+    count: u16,
+}
+
+impl<T> Default for Interval<T>
+where
+    T: Copy + Ord + Default + Into<i32> + TryFrom<i32>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Interval<T>
+where
+    T: Copy + Ord + Default + Into<i32> + TryFrom<i32>,
+{
+    pub fn new() -> Self {
+        Self {
+            min: T::default(),
+            max: T::default(),
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    pub fn add(&mut self, value: T) {
+        if self.count == 0 || value < self.min {
+            self.min = value;
+        }
+        if self.count == 0 || value > self.max {
+            self.max = value;
+        }
+        self.sum += value.into();
+        self.count = self.count.saturating_add(1);
+    }
+
+    /// Sum the interval up and start a new one.
+    pub fn take(&mut self) -> Option<payload::Summary<T>> {
+        let interval = mem::take(self);
+        if interval.count == 0 {
+            return None;
+        }
+
+        Some(payload::Summary {
+            min: interval.min,
+            max: interval.max,
+            // it's between min and max, so it always fits
+            mean: T::try_from(interval.sum / interval.count as i32).unwrap_or(interval.min),
+            count: cmp::min(interval.count, u8::MAX as u16) as u8,
+        })
+    }
+}
+
 pub struct SensorData {
     pub temperature: HistoryBuffer<i16, U8>,
     pub humidity: HistoryBuffer<u16, U8>,
     pub co2: HistoryBuffer<u16, U8>,
     pub num_points: u8,
+    // CO2 readings are left out while the sensor warms up, or when they're implausible
+    pub co2_points: u8,
+    pub co2_warming_up: bool,
     pub avgs: Averages,
+    pub temperature_interval: Interval<i16>,
+    pub humidity_interval: Interval<u16>,
+    pub co2_interval: Interval<u16>,
+    /// last reading of the CO2 sensor, for its temperature and condition
+    pub co2_sensor: Option<co2::Reading>,
+}
+
+impl Default for SensorData {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SensorData {
@@ -73,6 +221,35 @@ impl SensorData {
             co2: HistoryBuffer::new_with(0),
             avgs: Averages::new(),
             num_points: 0,
+            co2_points: 0,
+            co2_warming_up: true,
+            temperature_interval: Interval::new(),
+            humidity_interval: Interval::new(),
+            co2_interval: Interval::new(),
+            co2_sensor: None,
+        }
+    }
+
+    pub fn take_summaries(&mut self) -> payload::Summaries {
+        payload::Summaries {
+            temperature: self.temperature_interval.take(),
+            humidity: self.humidity_interval.take(),
+            co2: self.co2_interval.take(),
+        }
+    }
+
+    pub fn co2_condition(&self) -> Option<co2::Condition> {
+        self.co2_sensor.map(|reading| reading.condition)
+    }
+
+    /// Whether the CO2 sensor's idea of the temperature is too far off from the average.
+    pub fn temperature_mismatch(&self) -> bool {
+        match self.co2_sensor.and_then(|reading| reading.temperature) {
+            Some(temperature) if self.num_points > 0 => {
+                let diff = self.avgs.temperature as i32 - temperature as i32;
+                diff.abs() > TEMPERATURE_CROSS_CHECK as i32
+            }
+            _ => false,
         }
     }
 
@@ -80,7 +257,9 @@ impl SensorData {
         self.avgs.temperature =
             self.temperature.as_slice().iter().sum::<i16>() / self.num_points as i16;
         self.avgs.humidity = self.humidity.as_slice().iter().sum::<u16>() / self.num_points as u16;
-        self.avgs.co2 = self.co2.as_slice().iter().sum::<u16>() / self.num_points as u16;
+        if self.co2_points > 0 {
+            self.avgs.co2 = self.co2.as_slice().iter().sum::<u16>() / self.co2_points as u16;
+        }
     }
 }
 
@@ -88,6 +267,7 @@ struct ErrorData {
     temperature: bool,
     humidity: bool,
     co2: bool,
+    radio: bool,
 }
 
 impl ErrorData {
@@ -96,6 +276,7 @@ impl ErrorData {
             temperature: false,
             humidity: false,
             co2: false,
+            radio: false,
         }
     }
 }
@@ -105,7 +286,11 @@ pub struct SystemData {
     ticks_since_last_tx: u32,
     ticks_since_last_read: u32,
     send_tx_now: bool,
+    tx_interval: u32,
+    tx_sequence: u8,
+    last_payload: Option<payload::Payload>,
     read_sensors_now: bool,
+    rng: rng::Rng,
     sensors: SensorData,
     errors: ErrorData,
 }
@@ -117,11 +302,71 @@ impl SystemData {
             ticks_since_last_tx: 0,
             ticks_since_last_read: 0,
             send_tx_now: false,
+            tx_interval: TICKS_UNTIL_RADIO_TX,
+            tx_sequence: 0,
+            last_payload: None,
             read_sensors_now: false,
+            rng: rng::Rng::new(0),
             sensors: SensorData::new(),
             errors: ErrorData::new(),
         }
     }
+
+    fn current_payload(&self) -> payload::Payload {
+        let has_data = self.sensors.num_points > 0;
+        payload::Payload {
+            sequence: self.tx_sequence,
+            temperature: Some(self.sensors.avgs.temperature)
+                .filter(|_| has_data && !self.errors.temperature),
+            humidity: Some(self.sensors.avgs.humidity)
+                .filter(|_| has_data && !self.errors.humidity),
+            co2: Some(self.sensors.avgs.co2).filter(|_| {
+                self.sensors.co2_points > 0 && !self.sensors.co2_warming_up && !self.errors.co2
+            }),
+            co2_warming_up: self.sensors.co2_warming_up,
+            summaries: None,
+        }
+    }
+
+    fn next_payload(&mut self) -> payload::Payload {
+        let mut payload = self.current_payload();
+        if cfg!(feature = "extended-payload") {
+            payload.summaries = Some(self.sensors.take_summaries());
+        }
+        self.tx_sequence = self.tx_sequence.wrapping_add(1);
+        payload
+    }
+
+    /// Whether anything moved past `thresholds` (or came/went) since the last transmission.
+    fn needs_report(&self, thresholds: &Averages) -> bool {
+        let last = match self.last_payload {
+            Some(last) => last,
+            None => return true,
+        };
+        let now = self.current_payload();
+
+        moved(now.temperature, last.temperature, thresholds.temperature)
+            || moved(now.humidity, last.humidity, thresholds.humidity)
+            || moved(now.co2, last.co2, thresholds.co2)
+    }
+}
+
+fn moved<T: Into<i32>>(current: Option<T>, last: Option<T>, threshold: T) -> bool {
+    match (current, last) {
+        (Some(current), Some(last)) => (current.into() - last.into()).abs() >= threshold.into(),
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+fn next_tx_interval(rng: &mut rng::Rng) -> u32 {
+    // with report-by-exception, the regular transmissions are only heartbeats
+    let interval = if REPORT_THRESHOLDS.is_some() {
+        TICKS_UNTIL_HEARTBEAT
+    } else {
+        TICKS_UNTIL_RADIO_TX
+    };
+    rng.between(interval - TX_JITTER_TICKS, interval + TX_JITTER_TICKS)
 }
 
 static TIMER_TIM2: Move<Timer<stm32::TIM2>, stm32::Interrupt> =
@@ -132,34 +377,119 @@ lazy_static! {
         Mutex::new(RefCell::new(Cell::new(SystemData::new())));
 }
 
+lazy_static! {
+    static ref RADIO: Mutex<RefCell<Option<peripherals::RadioHeadASK>>> =
+        Mutex::new(RefCell::new(None));
+}
+
 lazy_static! {
     static ref UART_BUFFER: Mutex<RefCell<ringbuffer::RingBuffer<u8>>> =
         Mutex::new(RefCell::new(ringbuffer::RingBuffer::new()));
 }
 
-fn send_radio_packet<T>(radio: &mut peripherals::RadioHeadASK, id: u8, value: T) {
-    radio
-        .send_packet(0xff, 0xff, id, 0, &num_to_bytes(value))
-        .unwrap();
+fn send_radio_packet<R, T>(
+    radio: &mut R,
+    address: u8,
+    id: u8,
+    flags: u8,
+    value: T,
+    gap_ms: u32,
+) -> Result<(), R::Error>
+where
+    R: RfEncoder,
+    R::Error: From<rf::Error>,
+    T: wire::Encode,
+{
+    let header = rf::Header {
+        from: address,
+        to: DESTINATION_ADDRESS,
+        id,
+        flags,
+    };
+    let mut frame = radio.encode(&header, &wire::to_vec(&value))?;
+    frame.lead_in(frame.ticks(gap_ms)?);
+    radio.send_raw(frame)
 }
 
-fn num_to_bytes<T>(value: T) -> Vec<u8, U4> {
-    let v: *const T = &value;
-    let b_val: *const u8 = v as *const _;
-    // This is badass unsafe. Just doing it because I didn't want yet another dependency
-    let slice: &[u8] = unsafe { slice::from_raw_parts(b_val, mem::size_of::<T>()) };
+fn send_radio_data<R>(
+    radio: &mut R,
+    address: u8,
+    avgs: &Averages,
+    payload: &payload::Payload,
+    mac_counter: Option<u32>,
+) -> Result<(), R::Error>
+where
+    R: RfEncoder,
+    R::Error: From<rf::Error>,
+{
+    let mut payload = *payload;
+    let mut nexus_sent = false;
+
+    if cfg!(feature = "nexus") {
+        // rtl_433 understands this one, so only CO2 has to go over RadioHead
+        if let Some(temperature) = payload.temperature {
+            let frame = nexus::encode(&nexus::Reading {
+                id: address,
+                channel: NEXUS_CHANNEL,
+                battery_ok: true,
+                temperature,
+                humidity: payload.humidity,
+            })?;
+            radio.send_raw(frame)?;
+
+            payload.temperature = None;
+            payload.humidity = None;
+            nexus_sent = true;
+        }
+    }
+
+    let mut header = rf::Header {
+        from: address,
+        to: DESTINATION_ADDRESS,
+        id: payload.sequence,
+        flags: 0,
+    };
 
-    Vec::from_slice(slice).unwrap()
+    if cfg!(feature = "legacy-payload") {
+        // one packet per sensor, for receivers which haven't been updated yet.
+        // the ID is taken, so the sequence number goes in the (application) low bits of the flags
+        let flags = payload.sequence & 0x0f;
+        let mut gap_ms = 0;
+        // they've always carried the averages, even when a sensor fails
+        if !nexus_sent {
+            send_radio_packet(radio, address, 0xed, flags, avgs.temperature, gap_ms)?;
+            gap_ms = LEGACY_PACKET_GAP_MS;
+            send_radio_packet(radio, address, 0xee, flags, avgs.humidity, gap_ms)?;
+        }
+        send_radio_packet(radio, address, 0xef, flags, avgs.co2, gap_ms)
+    } else if let (Some(key), Some(counter)) = (NODE_KEY, mac_counter) {
+        header.flags = auth::FLAG_AUTHENTICATED;
+        let sealed = auth::seal(
+            &key,
+            [header.to, header.from, header.id, header.flags],
+            &payload.encode(),
+            counter,
+        )
+        .map_err(|_| rf::Error::PayloadTooLong)?;
+
+        radio.send_frame(&header, &sealed)
+    } else {
+        radio.send_frame(&header, &payload.encode())
+    }
 }
 
 fn write_value<T, E>(
     history: &mut HistoryBuffer<T, U8>,
+    interval: &mut Interval<T>,
     error_flag: &mut bool,
     data: Result<T, E>,
-) {
+) where
+    T: Copy + Ord + Default + Into<i32> + TryFrom<i32>,
+{
     match data {
         Ok(d) => {
             history.write(d);
+            interval.add(d);
             *error_flag = false;
         }
         Err(_) => {
@@ -168,6 +498,64 @@ fn write_value<T, E>(
     }
 }
 
+// ticks between range-test frames which take `airtime_us` each
+fn range_test_interval(airtime_us: u32) -> u32 {
+    let interval_us = airtime_us as u64 * 1000 / DUTY_CYCLE as u64;
+    let ticks = (interval_us * TICKS_PER_SECOND as u64).div_ceil(1_000_000);
+    cmp::max(ticks as u32, TICKS_UNTIL_RANGE_TEST_TX)
+}
+
+/// Send numbered test frames forever (see `range_test`), instead of readings.
+fn run_range_test<I>(ui: &mut ui::Ui<I>, delay: &mut Delay, address: u8) -> !
+where
+    I: WriteOnlyDataCommand,
+{
+    // these still have to stay within the duty cycle
+    let mut airtime = airtime::AirtimeBudget::new(DUTY_CYCLE);
+    let interval = free(|cs| match RADIO.borrow(cs).borrow_mut().as_ref() {
+        Some(radio) => range_test_interval(radio.airtime_us(range_test::payload(0).len())),
+        None => TICKS_UNTIL_RANGE_TEST_TX,
+    });
+    let mut sequence = 0;
+    let mut next_tx = 0;
+    let mut waiting = false;
+
+    loop {
+        let ticks_since_reset = free(|cs| {
+            let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
+            data.get_mut().ticks_since_reset
+        });
+
+        if ticks_since_reset >= next_tx {
+            next_tx = ticks_since_reset + interval;
+
+            let header = rf::Header {
+                from: address,
+                to: DESTINATION_ADDRESS,
+                id: sequence as u8,
+                flags: 0,
+            };
+            let now_s = ticks_since_reset / TICKS_PER_SECOND;
+            let result = free(|cs| match RADIO.borrow(cs).borrow_mut().as_mut() {
+                Some(radio) => airtime::Metered::new(radio, &mut airtime, now_s)
+                    .send_frame(&header, &range_test::payload(sequence)),
+                None => Ok(()),
+            });
+
+            // only frames which went out are counted, so that gaps are real losses
+            waiting = result.is_err();
+            if result.is_ok() {
+                sequence += 1;
+            }
+        }
+
+        ui.clear();
+        ui.draw_range_test(sequence, waiting);
+        ui.flush();
+        delay.delay_ms(10_u16);
+    }
+}
+
 #[entry]
 fn main() -> ! {
     if let (Some(p), Some(mut cp)) = (stm32::Peripherals::take(), cortex_m::Peripherals::take()) {
@@ -189,7 +577,7 @@ fn main() -> ! {
         ui.log_to_screen("Display init'd");
 
         // TIM2 is used as a system timer
-        let mut tim2 = Timer::tim2(p.TIM2, 5.hz(), clocks);
+        let mut tim2 = Timer::tim2(p.TIM2, TICKS_PER_SECOND.hz(), clocks);
         tim2.listen(Event::TimeOut);
         // move the timer to the exception handler
         TIMER_TIM2.try_move(tim2).ok();
@@ -204,51 +592,184 @@ fn main() -> ! {
 
         ui.log_to_screen("Interrupts set");
 
-        let (mut temperature_sensor, mut humidity_sensor, mut radio, mut uart) = peripherals::setup(
-            gpioa,
-            i2c_bus.acquire_i2c(),
-            clocks,
-            p.TIM1,
-            p.USART1
-        );
+        let (mut temperature_sensor, mut humidity_sensor, radio, uart, key) =
+            peripherals::setup(gpioa, i2c_bus.acquire_i2c(), clocks, p.TIM1, p.USART1);
+        let mut co2_sensor = peripherals::setup_co2_sensor(uart, i2c_bus.acquire_i2c());
+
+        // the radio is shared with the TIM1 interrupt, which clocks out the bits
+        free(|cs| {
+            *RADIO.borrow(cs).borrow_mut() = Some(radio);
+        });
+
+        NVIC::unpend(stm32::Interrupt::TIM1_UP_TIM10);
+        unsafe {
+            NVIC::unmask(stm32::Interrupt::TIM1_UP_TIM10);
+        };
 
         ui.log_to_screen("Peripherals init'd");
 
+        if co2_sensor.init(CO2_ABC).is_err() {
+            ui.log_to_screen("CO2 setup ERR");
+        }
+
+        let uid = node::unique_id();
+        let address = NODE_ADDRESS.unwrap_or_else(|| node::address_from_unique_id(&uid));
+
+        // every node gets different random numbers
+        free(|cs| {
+            let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
+            data.get_mut().rng = rng::Rng::new(node::seed_from_unique_id(&uid));
+        });
+
+        let mut text: String<U32> = String::new();
+        uwrite!(&mut text, "Node address: {}", address).unwrap();
+        ui.log_to_screen(&text);
+
+        // hold KEY down while booting to get there
+        if cfg!(feature = "range-test") || key.is_low().unwrap_or(false) {
+            ui.log_to_screen("Range test mode");
+            run_range_test(&mut ui, &mut delay, address);
+        }
+
+        let mut airtime = airtime::AirtimeBudget::new(DUTY_CYCLE);
+
+        // readings which are due in the meantime are sent after it
+        if let Some((signal, duration_ms)) = ANTENNA_TEST {
+            // it's charged in full, even though a square wave is only on half of the time
+            let duration_ms = cmp::min(duration_ms, radiohead_ask::MAX_TEST_DURATION_MS);
+            let duration_ms = cmp::min(duration_ms, airtime.available_us() / 1000);
+
+            let result = free(|cs| match RADIO.borrow(cs).borrow_mut().as_mut() {
+                Some(radio) => radio.start_test(signal, duration_ms),
+                None => Ok(()),
+            });
+            if result.is_ok() {
+                airtime.spend(duration_ms * 1000);
+            }
+            ui.log_to_screen(if result.is_ok() {
+                "Antenna test"
+            } else {
+                "Antenna test ERR"
+            });
+        }
+
+        // only touch the flash if it's needed
+        let mut mac_counter = match NODE_KEY {
+            Some(_) => Some(nvm::MonotonicCounter::new(p.FLASH)),
+            None => None,
+        };
+
+        let mut co2_filter = co2::PlausibilityFilter::new(co2_sensor.max_ppm(), CO2_MAX_RATE);
+        let co2_warmup_ticks = co2_sensor.warmup_s() * TICKS_PER_SECOND;
+        let mut last_tx_airtime_us = 0;
+        let mut tx_deferred = false;
+
         #[cfg(debug_assertions)]
         let itm = &mut cp.ITM.stim[0];
 
         // Main loop
         loop {
-            let (read_sensors_now, send_tx_now) = free(|cs| {
+            let (read_sensors_now, send_tx_now, ticks_since_reset) = free(|cs| {
                 let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
                 let data = data.get_mut();
-                (data.read_sensors_now, data.send_tx_now)
+                (
+                    data.read_sensors_now,
+                    data.send_tx_now,
+                    data.ticks_since_reset,
+                )
             });
+            let now_s = ticks_since_reset / TICKS_PER_SECOND;
+
+            let mut co2 = None;
 
             if read_sensors_now {
+                // the MH-Z19B gets to answer while the other sensors are read
+                if let Err(e) = Co2Sensor::start_request(&mut co2_sensor) {
+                    co2 = Some(Err(e));
+                }
                 let temperature = temperature_sensor.ambient_temperature();
                 let humidity = humidity_sensor.perform_measurement(&mut delay);
-                let co2 = mhz19b::request_reading(&mut uart);
 
                 free(|cs| {
                     let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
-                    let mut data = data.get_mut();
+                    let data = data.get_mut();
 
                     data.read_sensors_now = false;
 
+                    let temperature = temperature.map(|v| (v * 100.0) as i16);
+                    let humidity = humidity.map(|v| v.humidity / 10);
+                    // a Sensirion sensor's last reading stands in for those which failed
+                    #[cfg(any(feature = "scd4x", feature = "scd30"))]
+                    let (temperature, humidity) = {
+                        let last = data.sensors.co2_sensor;
+                        (
+                            temperature.or_else(|e| last.and_then(|r| r.temperature).ok_or(e)),
+                            humidity.or_else(|e| last.and_then(|r| r.humidity).ok_or(e)),
+                        )
+                    };
+
                     write_value(
                         &mut data.sensors.temperature,
+                        &mut data.sensors.temperature_interval,
                         &mut data.errors.temperature,
-                        temperature.map(|v| (v * 100.0) as i16),
+                        temperature,
                     );
                     write_value(
                         &mut data.sensors.humidity,
+                        &mut data.sensors.humidity_interval,
                         &mut data.errors.humidity,
-                        humidity.map(|v| v.humidity / 10),
+                        humidity,
                     );
-                    write_value(&mut data.sensors.co2, &mut data.errors.co2, co2);
                     data.sensors.num_points = cmp::min(data.sensors.num_points + 1, 8);
                     data.sensors.recalc_averages();
+                });
+            }
+
+            if co2.is_none() {
+                // the trait's, the MH-Z19B's own `poll` returns its raw reading
+                co2 = match Co2Sensor::poll(&mut co2_sensor) {
+                    Ok(reading) => Some(Ok(reading)),
+                    Err(nb::Error::Other(e)) => Some(Err(e)),
+                    Err(nb::Error::WouldBlock) => None,
+                };
+            }
+
+            // the readings are complete once CO2 is in
+            if let Some(co2) = co2 {
+                // what the sensor says about preheating (undocumented for the MH-Z19B) is only
+                // logged, the warm-up time is what counts
+                let warming_up = ticks_since_reset < co2_warmup_ticks;
+
+                free(|cs| {
+                    let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
+                    let data = data.get_mut();
+
+                    // failures still count while warming up, readings don't
+                    let accepted = match &co2 {
+                        Ok(reading) => !warming_up && co2_filter.accept(reading.co2_ppm, now_s),
+                        Err(_) => true,
+                    };
+                    if accepted {
+                        write_value(
+                            &mut data.sensors.co2,
+                            &mut data.sensors.co2_interval,
+                            &mut data.errors.co2,
+                            co2.as_ref().map(|reading| reading.co2_ppm),
+                        );
+                        if co2.is_ok() {
+                            data.sensors.co2_points = cmp::min(data.sensors.co2_points + 1, 8);
+                        }
+                    }
+                    data.sensors.co2_warming_up = warming_up;
+                    data.sensors.co2_sensor = co2.as_ref().ok().copied();
+                    data.sensors.recalc_averages();
+
+                    if let Some(thresholds) = REPORT_THRESHOLDS {
+                        if data.needs_report(&thresholds) {
+                            data.send_tx_now = true;
+                            data.ticks_since_last_tx = 0;
+                        }
+                    }
 
                     #[cfg(debug_assertions)]
                     iprintln!(
@@ -266,38 +787,162 @@ fn main() -> ! {
                         data.sensors.avgs.humidity,
                         data.sensors.avgs.co2
                     );
+                    #[cfg(debug_assertions)]
+                    {
+                        if let Some(reading) = data.sensors.co2_sensor {
+                            iprintln!(
+                                itm,
+                                "CO2 sensor T:{:?} H:{:?} {:?}",
+                                reading.temperature,
+                                reading.humidity,
+                                reading.condition
+                            );
+                            if !warming_up && reading.condition == co2::Condition::WarmingUp {
+                                iprintln!(itm, "CO2 sensor still preheating after warm-up");
+                            }
+                        }
+                        iprintln!(itm, "CO2: {} implausible", co2_filter.rejected);
+                        #[cfg(not(any(
+                            feature = "scd4x",
+                            feature = "scd30",
+                            feature = "senseair-s8"
+                        )))]
+                        {
+                            let stats = co2_sensor.stats();
+                            iprintln!(
+                                itm,
+                                "MH-Z19B: {} frames, {} bad checksums, {} bytes skipped",
+                                stats.frames,
+                                stats.bad_checksums,
+                                stats.discarded_bytes
+                            );
+                        }
+                    }
                 });
             }
 
             if send_tx_now {
-                free(|cs| {
-                    let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
-                    let mut data = data.get_mut();
+                airtime.advance(now_s);
+            }
 
-                    send_radio_packet(&mut radio, 0xed, data.sensors.avgs.temperature);
-                    delay.delay_ms(100u16);
-                    send_radio_packet(&mut radio, 0xee, data.sensors.avgs.humidity);
-                    delay.delay_ms(100u16);
-                    send_radio_packet(&mut radio, 0xef, data.sensors.avgs.co2);
+            // assume it'll take as long as the last one did; if it doesn't fit, wait
+            // for older transmissions to leave the window (send_tx_now stays set)
+            let defer_tx = send_tx_now && last_tx_airtime_us > airtime.available_us();
+            if defer_tx && !tx_deferred {
+                airtime.defer();
+            }
+            tx_deferred = defer_tx;
+
+            if send_tx_now && !defer_tx {
+                let (avgs, payload) = free(|cs| {
+                    let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
+                    let data = data.get_mut();
 
                     // reset send flag
                     data.send_tx_now = false;
+                    (data.sensors.avgs, data.next_payload())
+                });
+
+                // done outside of the critical section, since it may need to write to flash.
+                // once it has stopped, nothing more is sent (see `nvm`)
+                let counter = mac_counter.as_mut().map(|c| c.increment()).transpose();
+
+                // this only queues the packets, TIM1 takes care of sending them
+                let (result, spent_us) = free(|cs| {
+                    match (RADIO.borrow(cs).borrow_mut().as_mut(), counter) {
+                        (Some(radio), Ok(counter)) => {
+                            let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
+                            let rng = &mut data.get_mut().rng;
+
+                            // repeats count against the airtime budget too
+                            let mut metered = airtime::Metered::new(radio, &mut airtime, now_s);
+                            let mut radio =
+                                rf::Repeated::new(&mut metered, rng, TX_REPEATS, TX_REPEAT_GAP_MS);
+                            let result =
+                                send_radio_data(&mut radio, address, &avgs, &payload, counter);
+                            (result, metered.spent_us())
+                        }
+                        _ => (Ok(()), 0),
+                    }
+                });
+                last_tx_airtime_us = spent_us;
+
+                #[cfg(debug_assertions)]
+                {
+                    if let Err(e) = result {
+                        iprintln!(itm, "TX error: {:?}", e);
+                    }
+                    if let Err(e) = counter {
+                        iprintln!(itm, "MAC counter stopped ({:?}), not sending", e);
+                    }
+                    iprintln!(
+                        itm,
+                        "Airtime: {}/{} ms in the last hour ({} deferred, {} dropped)",
+                        airtime.used_us() / 1000,
+                        airtime.limit_us() / 1000,
+                        airtime.stats.deferred,
+                        airtime.stats.dropped
+                    );
+                }
+
+                free(|cs| {
+                    let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
+                    let data = data.get_mut();
+
+                    // report-by-exception compares against what actually went out
+                    if result.is_ok() && counter.is_ok() {
+                        data.last_payload = Some(payload);
+                    }
+                    // running out of airtime isn't the radio's fault
+                    data.errors.radio = counter.is_err()
+                        || matches!(result, Err(e) if e != rf::Error::DutyCycleExceeded);
                 });
             }
 
+            #[cfg(debug_assertions)]
+            {
+                let tx_done = free(|cs| {
+                    RADIO
+                        .borrow(cs)
+                        .borrow_mut()
+                        .as_mut()
+                        .is_some_and(|radio| radio.take_tx_done())
+                });
+                if tx_done {
+                    iprintln!(itm, "TX done");
+                }
+            }
+
             ui.clear();
 
             free(|cs| {
                 let mut data = SYSTEM_DATA.borrow(cs).borrow_mut();
-                ui.draw(&data.get_mut());
+                ui.draw(data.get_mut());
             });
 
             ui.flush();
             delay.delay_ms(10_u16);
         }
     }
+    panic!("Can't get peripherals!");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    // whatever was being sent (or an antenna test) mustn't keep the carrier on
+    peripherals::radio_off();
+
+    #[cfg(debug_assertions)]
+    {
+        let itm = unsafe { &mut *cortex_m::peripheral::ITM::ptr() };
+        iprintln!(&mut itm.stim[0], "{}", info);
+    }
+    #[cfg(not(debug_assertions))]
+    let _ = info;
+
     loop {
-        panic!("Can't get peripherals!");
+        atomic::compiler_fence(Ordering::SeqCst);
     }
 }
 
@@ -311,8 +956,9 @@ fn TIM2() {
 
                 data.ticks_since_reset += 1;
 
-                if data.ticks_since_last_tx > TICKS_UNTIL_RADIO_TX {
+                if data.ticks_since_last_tx > data.tx_interval {
                     data.ticks_since_last_tx = 0;
+                    data.tx_interval = next_tx_interval(&mut data.rng);
                     data.send_tx_now = true;
                 } else {
                     data.ticks_since_last_tx += 1;
@@ -331,6 +977,15 @@ fn TIM2() {
         .ok();
 }
 
+#[interrupt]
+fn TIM1_UP_TIM10() {
+    free(|cs| {
+        if let Some(radio) = RADIO.borrow(cs).borrow_mut().as_mut() {
+            radio.tick().ok();
+        }
+    });
+}
+
 #[interrupt]
 fn USART1() {
     let c = unsafe {
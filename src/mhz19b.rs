@@ -1,67 +1,646 @@
-use crate::syscalls;
-use core::fmt::Debug;
-use cortex_m::interrupt::free;
+// MH-Z19B CO2 sensor. It doesn't depend on anything else in here (the serial port and the
+// clock are passed in), so it can be reused, and tested off the board.
+
 use embedded_hal::serial::{Read, Write};
 use heapless::{consts::*, Vec};
 use nb::block;
 
+use crate::clock::Clock;
+
+const START_BYTE: u8 = 0xff;
+
+// how long the sensor gets to answer
+const RESPONSE_TIMEOUT_MS: u32 = 1000;
+// the sensor number, always 1 on the MH-Z19B
+const SENSOR: u8 = 0x01;
+
+// operating range, from the datasheet
+const MIN_TEMPERATURE_C: i16 = 0;
+const MAX_TEMPERATURE_C: i16 = 50;
+
+// bit of the status byte which is set once the sensor has warmed up (it's 0 while preheating).
+// Not in the datasheet, but that's how it behaves
+const STATUS_READY: u8 = 0x40;
+
+// span calibration isn't meant to be done with less than this (the datasheet suggests 2000)
+const MIN_SPAN_PPM: u16 = 1000;
+
 #[derive(Debug)]
 pub enum Error {
     IncompletePacket,
     WrongStartByte,
     WrongChecksum,
     Timeout,
+    Serial,
+    InvalidSpan,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    ReadConcentration = 0x86,
+    CalibrateZero = 0x87,
+    CalibrateSpan = 0x88,
+    SetAbc = 0x79,
+    SetRange = 0x99,
+}
+
+/// Upper limit of what the sensor measures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DetectionRange {
+    Ppm2000,
+    Ppm5000,
+    Ppm10000,
+}
+
+impl DetectionRange {
+    pub fn ppm(self) -> u16 {
+        match self {
+            DetectionRange::Ppm2000 => 2000,
+            DetectionRange::Ppm5000 => 5000,
+            DetectionRange::Ppm10000 => 10000,
+        }
+    }
+}
+
+/// Response to a "read concentration" command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mhz19bReading {
+    pub co2_ppm: u16,
+    /// inside the sensor, so usually a couple of degrees above ambient
+    pub temperature_c: i16,
+    /// undocumented, see `condition`
+    pub status: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Ready,
+    /// still preheating, going by the status byte. That's undocumented, so it's only a hint
+    WarmingUp,
+    /// running outside its operating temperature range
+    Degraded,
+}
+
+impl Mhz19bReading {
+    pub fn condition(&self) -> Condition {
+        if self.status & STATUS_READY == 0 {
+            Condition::WarmingUp
+        } else if !(MIN_TEMPERATURE_C..=MAX_TEMPERATURE_C).contains(&self.temperature_c) {
+            Condition::Degraded
+        } else {
+            Condition::Ready
+        }
+    }
 }
 
 fn calc_checksum(buf: &[u8]) -> u8 {
     1u8.wrapping_add(0xff - buf.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)))
 }
 
-pub fn parse_data(buf: &[u8]) -> Result<u16, Error> {
+// check the framing of a response
+fn check_frame(buf: &[u8]) -> Result<(), Error> {
     if buf.len() < 9 {
         Err(Error::IncompletePacket)
-    } else if buf[0] != 0xff {
+    } else if buf[0] != START_BYTE {
         Err(Error::WrongStartByte)
     } else if buf[8] != calc_checksum(&buf[1..8]) {
         Err(Error::WrongChecksum)
     } else {
-        Ok(((buf[2] as u16) << 8) | buf[3] as u16)
+        Ok(())
+    }
+}
+
+pub fn parse_data(buf: &[u8]) -> Result<Mhz19bReading, Error> {
+    check_frame(buf).map(|_| Mhz19bReading {
+        co2_ppm: ((buf[2] as u16) << 8) | buf[3] as u16,
+        temperature_c: buf[4] as i16 - 40,
+        status: buf[5],
+    })
+}
+
+/// Diagnostics for `FrameDecoder`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    /// valid frames
+    pub frames: u32,
+    /// bytes thrown away while looking for the start of a frame
+    pub discarded_bytes: u32,
+    /// frames which failed the checksum
+    pub bad_checksums: u32,
+}
+
+/// Picks "read concentration" responses (or acknowledgements of another command) out of the
+/// bytes coming from the sensor, however they're split up and whatever else comes with them.
+pub struct FrameDecoder {
+    buf: Vec<u8, U9>,
+    // responses start with the command they answer
+    command: Command,
+    pub stats: FrameStats,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::for_command(Command::ReadConcentration)
+    }
+
+    fn for_command(command: Command) -> Self {
+        Self {
+            buf: Vec::new(),
+            command,
+            stats: FrameStats::default(),
+        }
+    }
+
+    /// Forget about any partial frame.
+    pub fn reset(&mut self) {
+        // not `clear`: heapless 0.5's `truncate` indexes past the end of the buffer
+        self.buf = Vec::new();
+    }
+
+    /// Returns a reading once `byte` completes a valid frame.
+    pub fn feed(&mut self, byte: u8) -> Option<Mhz19bReading> {
+        self.feed_frame(byte)
+            .and_then(|frame| parse_data(&frame).ok())
+    }
+
+    // returns the frame once `byte` completes a valid one
+    fn feed_frame(&mut self, byte: u8) -> Option<[u8; 9]> {
+        // there's always room, since complete frames don't stay in the buffer
+        self.buf.push(byte).ok();
+
+        loop {
+            if !self.at_frame_start() {
+                self.skip();
+            } else if self.buf.len() < self.buf.capacity() {
+                return None;
+            } else {
+                match check_frame(&self.buf) {
+                    Ok(()) => {
+                        let mut frame = [0; 9];
+                        frame.copy_from_slice(&self.buf);
+                        self.reset();
+                        self.stats.frames += 1;
+                        return Some(frame);
+                    }
+                    Err(_) => {
+                        // there may be a frame starting somewhere in this one
+                        self.stats.bad_checksums += 1;
+                        self.skip();
+                    }
+                }
+            }
+        }
+    }
+
+    // whether the buffer holds the beginning of a frame (or nothing)
+    fn at_frame_start(&self) -> bool {
+        let header = [START_BYTE, self.command as u8];
+        self.buf.iter().zip(header.iter()).all(|(a, b)| a == b)
+    }
+
+    // drop the first byte
+    fn skip(&mut self) {
+        self.buf.rotate_left(1);
+        self.buf.pop();
+        self.stats.discarded_bytes += 1;
     }
 }
 
-pub fn request_reading<U, E>(uart: &mut U) -> Result<u16, Error>
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// MH-Z19B driver. `serial` is where commands go and responses come from; it can be the UART
+/// itself, or whatever the UART interrupt fills up.
+///
+/// Readings don't wait around for the sensor: `start_request`, then `poll` until there's a
+/// result.
+pub struct Mhz19b<S, C> {
+    serial: S,
+    clock: C,
+    range: DetectionRange,
+    decoder: FrameDecoder,
+    // when the request went out, in ms
+    started: Option<u32>,
+}
+
+impl<S, C> Mhz19b<S, C>
 where
-    U: Read<u8, Error = E> + Write<u8, Error = E>,
-    E: Debug,
+    S: Read<u8> + Write<u8>,
+    C: Clock,
 {
-    // we can clean up the UART buffer
-    free(|cs| {
-        let mut input_buffer = crate::UART_BUFFER.borrow(cs).borrow_mut();
-        input_buffer.clear();
-    });
+    pub fn new(serial: S, clock: C) -> Self {
+        Self {
+            serial,
+            clock,
+            range: DetectionRange::Ppm5000,
+            decoder: FrameDecoder::new(),
+            started: None,
+        }
+    }
+
+    /// Only sets what `range` returns, see `set_detection_range` for the sensor itself.
+    pub fn detection_range(mut self, range: DetectionRange) -> Self {
+        self.range = range;
+        self
+    }
+
+    /// The detection range, as last set (5000 ppm, the sensor's default, otherwise).
+    pub fn range(&self) -> DetectionRange {
+        self.range
+    }
+
+    pub fn release(self) -> (S, C) {
+        (self.serial, self.clock)
+    }
+
+    /// Take the current concentration as 400 ppm. The sensor should have been in fresh air
+    /// for at least 20 minutes.
+    pub fn calibrate_zero(&mut self) -> Result<(), Error> {
+        self.send_command(Command::CalibrateZero, [0; 5])
+    }
 
-    for c in &[0xFF, 0x01, 0x86, 0, 0, 0, 0, 0, 0x79] {
-        block!(uart.write(*c)).unwrap();
+    /// Take the current concentration as `span_ppm`. Zero calibration has to be done first.
+    pub fn calibrate_span(&mut self, span_ppm: u16) -> Result<(), Error> {
+        if span_ppm < MIN_SPAN_PPM {
+            return Err(Error::InvalidSpan);
+        }
+        let [high, low] = span_ppm.to_be_bytes();
+        self.send_command(Command::CalibrateSpan, [high, low, 0, 0, 0])
     }
-    block!(uart.flush()).unwrap();
 
-    let mut buf = Vec::<u8, U9>::new();
-    let mut start_time = syscalls::get_current_ticks();
+    /// Automatic baseline correction takes the lowest reading of every 24 h as 400 ppm. It's on
+    /// by default, and only right for places which get some fresh air every day.
+    pub fn set_abc(&mut self, enabled: bool) -> Result<(), Error> {
+        let flag = if enabled { 0xa0 } else { 0x00 };
+        self.command_with_ack(Command::SetAbc, [flag, 0, 0, 0, 0])
+    }
 
-    loop {
-        buf.extend_from_slice(&syscalls::uart_buffer_pop::<U9>())
-            .unwrap();
+    pub fn set_detection_range(&mut self, range: DetectionRange) -> Result<(), Error> {
+        let [high, low] = range.ppm().to_be_bytes();
+        self.command_with_ack(Command::SetRange, [0, 0, 0, high, low])?;
+        self.range = range;
+        Ok(())
+    }
 
-        let current_ticks = syscalls::get_current_ticks();
-        if start_time > current_ticks {
-            // the tick counter wrapped around
-            start_time = current_ticks;
+    /// Ask for a reading. Anything still pending is forgotten.
+    pub fn start_request(&mut self) -> Result<(), Error> {
+        // whatever came in before is of no use
+        while self.serial.read().is_ok() {}
+
+        self.decoder.reset();
+        self.started = None;
+        self.send_command(Command::ReadConcentration, [0; 5])?;
+        self.started = Some(self.clock.now_ms());
+        Ok(())
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.decoder.stats
+    }
+
+    /// Whether a request is waiting for its response.
+    pub fn is_pending(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// `WouldBlock` until the response is in, or `Timeout` if the sensor doesn't answer
+    /// within a second. It's also `WouldBlock` when nothing was requested.
+    pub fn poll(&mut self) -> nb::Result<Mhz19bReading, Error> {
+        let started = self.started.ok_or(nb::Error::WouldBlock)?;
+
+        while let Ok(byte) = self.serial.read() {
+            if let Some(reading) = self.decoder.feed(byte) {
+                self.started = None;
+                return Ok(reading);
+            }
         }
 
-        if buf.len() == 9 {
-            break Ok(parse_data(&buf)?);
-        } else if current_ticks - start_time > 5 {
-            break Err(Error::Timeout);
+        if self.clock.now_ms().wrapping_sub(started) > RESPONSE_TIMEOUT_MS {
+            self.started = None;
+            Err(nb::Error::Other(Error::Timeout))
+        } else {
+            Err(nb::Error::WouldBlock)
         }
     }
+
+    // send `command` and wait for the sensor to acknowledge it, for up to a second. A reading
+    // which is still pending is forgotten
+    fn command_with_ack(&mut self, command: Command, args: [u8; 5]) -> Result<(), Error> {
+        while self.serial.read().is_ok() {}
+        self.started = None;
+        self.send_command(command, args)?;
+
+        let started = self.clock.now_ms();
+        let mut decoder = FrameDecoder::for_command(command);
+        while self.clock.now_ms().wrapping_sub(started) <= RESPONSE_TIMEOUT_MS {
+            if let Ok(byte) = self.serial.read() {
+                if decoder.feed_frame(byte).is_some() {
+                    return Ok(());
+                }
+            }
+        }
+
+        if decoder.stats.bad_checksums > 0 {
+            Err(Error::WrongChecksum)
+        } else {
+            Err(Error::Timeout)
+        }
+    }
+
+    fn send_command(&mut self, command: Command, args: [u8; 5]) -> Result<(), Error> {
+        let mut buf = [START_BYTE, SENSOR, command as u8, 0, 0, 0, 0, 0, 0];
+        buf[3..8].copy_from_slice(&args);
+        buf[8] = calc_checksum(&buf[1..8]);
+
+        for c in &buf {
+            block!(self.serial.write(*c)).map_err(|_| Error::Serial)?;
+        }
+        block!(self.serial.flush()).map_err(|_| Error::Serial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+    use core::convert::Infallible;
+    use std::{
+        cell::{Cell, RefCell},
+        collections::VecDeque,
+        rc::Rc,
+        vec::Vec as StdVec,
+    };
+
+    #[derive(Default)]
+    struct Line {
+        // what the sensor sent, not read yet
+        rx: VecDeque<u8>,
+        // what was sent to the sensor
+        tx: StdVec<u8>,
+        // what the sensor answers to the next commands, in order
+        replies: VecDeque<StdVec<u8>>,
+    }
+
+    // the sensor's end of the UART, which answers once a command is flushed
+    #[derive(Clone, Default)]
+    struct Serial(Rc<RefCell<Line>>);
+
+    impl Serial {
+        fn reply(&self, bytes: &[u8]) {
+            self.0.borrow_mut().replies.push_back(bytes.to_vec());
+        }
+
+        // as if it came in on its own
+        fn receive(&self, bytes: &[u8]) {
+            self.0.borrow_mut().rx.extend(bytes);
+        }
+
+        fn sent(&self) -> StdVec<u8> {
+            std::mem::take(&mut self.0.borrow_mut().tx)
+        }
+    }
+
+    impl Read<u8> for Serial {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.0
+                .borrow_mut()
+                .rx
+                .pop_front()
+                .ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    impl Write<u8> for Serial {
+        type Error = Infallible;
+
+        fn write(&mut self, word: u8) -> nb::Result<(), Infallible> {
+            self.0.borrow_mut().tx.push(word);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            let mut line = self.0.borrow_mut();
+            if let Some(reply) = line.replies.pop_front() {
+                line.rx.extend(reply);
+            }
+            Ok(())
+        }
+    }
+
+    // goes forward by `step` every time it's read, so that waiting comes to an end
+    #[derive(Clone)]
+    struct TestClock {
+        now: Rc<Cell<u32>>,
+        step: u32,
+    }
+
+    impl TestClock {
+        fn new(step: u32) -> Self {
+            Self {
+                now: Rc::new(Cell::new(0)),
+                step,
+            }
+        }
+
+        fn set(&self, now_ms: u32) {
+            self.now.set(now_ms);
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now_ms(&self) -> u32 {
+            let now = self.now.get();
+            self.now.set(now.wrapping_add(self.step));
+            now
+        }
+    }
+
+    fn sensor(step: u32) -> (Mhz19b<Serial, TestClock>, Serial, TestClock) {
+        let serial = Serial::default();
+        let clock = TestClock::new(step);
+        (Mhz19b::new(serial.clone(), clock.clone()), serial, clock)
+    }
+
+    fn ack(command: Command) -> [u8; 9] {
+        let mut buf = [START_BYTE, command as u8, 0, 0, 0, 0, 0, 0, 0];
+        buf[8] = calc_checksum(&buf[1..8]);
+        buf
+    }
+
+    fn response(co2_ppm: u16, temperature_c: i16, status: u8) -> [u8; 9] {
+        let [high, low] = co2_ppm.to_be_bytes();
+        let mut buf = [
+            START_BYTE,
+            Command::ReadConcentration as u8,
+            high,
+            low,
+            (temperature_c + 40) as u8,
+            status,
+            0,
+            0,
+            0,
+        ];
+        buf[8] = calc_checksum(&buf[1..8]);
+        buf
+    }
+
+    #[test]
+    fn frames_are_picked_out_of_garbage() {
+        let mut rng = Rng::new(0x1234_5678);
+
+        for _ in 0..200 {
+            let mut stream = StdVec::new();
+            let mut expected = StdVec::new();
+
+            for _ in 0..rng.between(1, 5) {
+                // plenty of start and command bytes, to throw it off. A whole header could make
+                // up a valid frame by chance (the checksum is a single byte) and take the start
+                // of the next one with it, so there are none of those
+                for _ in 0..rng.between(0, 40) {
+                    let byte = match rng.between(0, 3) {
+                        0 => START_BYTE,
+                        1 => Command::ReadConcentration as u8,
+                        _ => rng.next_u32() as u8,
+                    };
+                    if stream.last() == Some(&START_BYTE)
+                        && byte == Command::ReadConcentration as u8
+                    {
+                        continue;
+                    }
+                    stream.push(byte);
+                }
+                let reading = Mhz19bReading {
+                    co2_ppm: rng.between(0, 10000) as u16,
+                    temperature_c: rng.between(0, 80) as i16 - 20,
+                    status: rng.next_u32() as u8,
+                };
+                stream.extend_from_slice(&response(
+                    reading.co2_ppm,
+                    reading.temperature_c,
+                    reading.status,
+                ));
+                expected.push(reading);
+            }
+
+            let mut decoder = FrameDecoder::new();
+            let decoded: StdVec<_> = stream.iter().filter_map(|b| decoder.feed(*b)).collect();
+            assert_eq!(decoded, expected);
+            assert_eq!(decoder.stats.frames as usize, expected.len());
+        }
+    }
+
+    #[test]
+    fn readings_are_polled_for() {
+        let (mut sensor, serial, _) = sensor(0);
+        assert!(matches!(sensor.poll(), Err(nb::Error::WouldBlock)));
+
+        serial.reply(&response(812, 25, STATUS_READY));
+        sensor.start_request().unwrap();
+        assert_eq!(serial.sent(), [0xff, 0x01, 0x86, 0, 0, 0, 0, 0, 0x79]);
+
+        let reading = sensor.poll().unwrap();
+        assert_eq!(reading.co2_ppm, 812);
+        assert_eq!(reading.temperature_c, 25);
+        assert_eq!(reading.condition(), Condition::Ready);
+        assert!(!sensor.is_pending());
+    }
+
+    #[test]
+    fn requests_time_out() {
+        let (mut sensor, _, clock) = sensor(0);
+
+        clock.set(u32::MAX - 100);
+        sensor.start_request().unwrap();
+        clock.set(RESPONSE_TIMEOUT_MS - 101);
+        assert!(matches!(sensor.poll(), Err(nb::Error::WouldBlock)));
+        clock.set(RESPONSE_TIMEOUT_MS - 100);
+        assert!(matches!(
+            sensor.poll(),
+            Err(nb::Error::Other(Error::Timeout))
+        ));
+        // it's over
+        assert!(matches!(sensor.poll(), Err(nb::Error::WouldBlock)));
+    }
+
+    #[test]
+    fn bad_checksums_are_skipped() {
+        let (mut sensor, serial, clock) = sensor(0);
+        let mut corrupted = response(812, 25, STATUS_READY);
+        corrupted[3] ^= 0x10;
+
+        sensor.start_request().unwrap();
+        serial.receive(&corrupted);
+        assert!(matches!(sensor.poll(), Err(nb::Error::WouldBlock)));
+        assert_eq!(sensor.stats().bad_checksums, 1);
+
+        serial.receive(&response(640, 24, STATUS_READY));
+        assert_eq!(sensor.poll().unwrap().co2_ppm, 640);
+
+        // nothing but a corrupted one is still a timeout
+        sensor.start_request().unwrap();
+        serial.receive(&corrupted);
+        clock.set(RESPONSE_TIMEOUT_MS + 1);
+        assert!(matches!(
+            sensor.poll(),
+            Err(nb::Error::Other(Error::Timeout))
+        ));
+    }
+
+    #[test]
+    fn split_frames_are_put_together() {
+        let (mut sensor, serial, _) = sensor(0);
+        let frame = response(1234, 30, STATUS_READY);
+
+        sensor.start_request().unwrap();
+        // some noise, a frame cut short, and then a whole one, bit by bit
+        serial.receive(&[0x00, 0x86, START_BYTE]);
+        serial.receive(&frame[..5]);
+        assert!(matches!(sensor.poll(), Err(nb::Error::WouldBlock)));
+        serial.receive(&frame[..3]);
+        assert!(matches!(sensor.poll(), Err(nb::Error::WouldBlock)));
+        serial.receive(&frame[3..]);
+        assert_eq!(sensor.poll().unwrap().co2_ppm, 1234);
+        assert_eq!(sensor.stats().frames, 1);
+    }
+
+    #[test]
+    fn stale_bytes_are_dropped() {
+        let (mut sensor, serial, _) = sensor(0);
+
+        // what came in before the request isn't the answer to it
+        serial.receive(&response(999, 20, STATUS_READY));
+        sensor.start_request().unwrap();
+        assert!(matches!(sensor.poll(), Err(nb::Error::WouldBlock)));
+    }
+
+    #[test]
+    fn settings_are_acknowledged() {
+        let (mut sensor, serial, _) = sensor(10);
+
+        serial.reply(&ack(Command::SetAbc));
+        sensor.set_abc(false).unwrap();
+        assert_eq!(serial.sent(), [0xff, 0x01, 0x79, 0, 0, 0, 0, 0, 0x86]);
+
+        serial.reply(&ack(Command::SetRange));
+        sensor.set_detection_range(DetectionRange::Ppm2000).unwrap();
+        assert_eq!(sensor.range(), DetectionRange::Ppm2000);
+
+        // an answer to something else doesn't count
+        serial.reply(&ack(Command::SetAbc));
+        assert!(matches!(
+            sensor.set_detection_range(DetectionRange::Ppm10000),
+            Err(Error::Timeout)
+        ));
+        assert_eq!(sensor.range(), DetectionRange::Ppm2000);
+
+        let mut corrupted = ack(Command::SetAbc);
+        corrupted[8] ^= 0x01;
+        serial.reply(&corrupted);
+        assert!(matches!(sensor.set_abc(true), Err(Error::WrongChecksum)));
+    }
 }
@@ -0,0 +1,597 @@
+// Modbus RTU master, for sensors on the UART (directly, or through an RS-485 transceiver). Like
+// `mhz19b`, it only needs a serial port and a clock, and doesn't wait around for responses.
+
+use embedded_hal::{
+    digital::v2::OutputPin,
+    serial::{Read, Write},
+};
+use heapless::{consts::*, Vec};
+use nb::block;
+
+use crate::{clock::Clock, pin::NoPin};
+
+/// The most registers a request can read.
+pub const MAX_REGISTERS: u16 = 8;
+
+/// What a request returns: the registers read, or the value written.
+pub type Registers = Vec<u16, U8>;
+
+// how long the slave gets to answer
+const RESPONSE_TIMEOUT_MS: u32 = 1000;
+
+// set in the function code of exception responses
+const EXCEPTION_FLAG: u8 = 0x80;
+
+// the longest response: slave, function, byte count, MAX_REGISTERS words, CRC
+type Frame = Vec<u8, U21>;
+
+#[derive(Debug)]
+pub enum Error {
+    Serial,
+    /// the RS-485 direction pin couldn't be set
+    DirectionPin,
+    Timeout,
+    /// the slave couldn't do what it was asked
+    Exception(Exception),
+    /// more than `MAX_REGISTERS`, or none at all
+    InvalidCount,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exception {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    SlaveDeviceFailure,
+    Acknowledge,
+    SlaveDeviceBusy,
+    Other(u8),
+}
+
+impl From<u8> for Exception {
+    fn from(code: u8) -> Self {
+        match code {
+            0x01 => Exception::IllegalFunction,
+            0x02 => Exception::IllegalDataAddress,
+            0x03 => Exception::IllegalDataValue,
+            0x04 => Exception::SlaveDeviceFailure,
+            0x05 => Exception::Acknowledge,
+            0x06 => Exception::SlaveDeviceBusy,
+            code => Exception::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    ReadHoldingRegisters = 0x03,
+    ReadInputRegisters = 0x04,
+    WriteSingleRegister = 0x06,
+}
+
+/// CRC-16/MODBUS, sent low byte first.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u16, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+// whether `buf` is the beginning of `frame` (or the other way around)
+fn is_prefix(buf: &[u8], frame: &[u8]) -> bool {
+    buf.iter().zip(frame.iter()).all(|(a, b)| a == b)
+}
+
+// what the response to the pending request looks like
+struct Expected {
+    // the bytes it starts with: the whole request for writes, which are echoed back
+    header: Frame,
+    len: usize,
+}
+
+/// Modbus RTU master. Requests go out with `read_input_registers` and the like, then the
+/// response is `poll`ed for. There's only ever one request pending; a new one replaces it.
+///
+/// Broadcasts (slave 0) aren't supported, since they don't get a response.
+pub struct Master<S, C, D = NoPin> {
+    serial: S,
+    clock: C,
+    // the transceiver's DE and /RE, high while sending
+    direction: Option<D>,
+    buf: Frame,
+    expected: Option<Expected>,
+    // when the request went out, in ms
+    started: u32,
+}
+
+impl<S, C> Master<S, C>
+where
+    S: Read<u8> + Write<u8>,
+    C: Clock,
+{
+    pub fn new(serial: S, clock: C) -> Self {
+        Self {
+            serial,
+            clock,
+            direction: None,
+            buf: Vec::new(),
+            expected: None,
+            started: 0,
+        }
+    }
+}
+
+impl<S, C, D> Master<S, C, D>
+where
+    S: Read<u8> + Write<u8>,
+    C: Clock,
+    D: OutputPin,
+{
+    /// Drive an RS-485 transceiver's DE and /RE (tied together) with `pin`: high while a
+    /// request goes out, low the rest of the time.
+    pub fn direction_pin<P>(self, mut pin: P) -> Result<Master<S, C, P>, Error>
+    where
+        P: OutputPin,
+    {
+        pin.set_low().map_err(|_| Error::DirectionPin)?;
+        Ok(Master {
+            serial: self.serial,
+            clock: self.clock,
+            direction: Some(pin),
+            buf: self.buf,
+            expected: self.expected,
+            started: self.started,
+        })
+    }
+
+    pub fn release(self) -> (S, C) {
+        (self.serial, self.clock)
+    }
+
+    pub fn read_input_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<(), Error> {
+        self.read_registers(Function::ReadInputRegisters, slave, address, count)
+    }
+
+    pub fn read_holding_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<(), Error> {
+        self.read_registers(Function::ReadHoldingRegisters, slave, address, count)
+    }
+
+    pub fn write_register(&mut self, slave: u8, address: u16, value: u16) -> Result<(), Error> {
+        let request = self.send(Function::WriteSingleRegister, slave, address, value)?;
+        self.expect(request, 8);
+        Ok(())
+    }
+
+    /// Whether a request is waiting for its response.
+    pub fn is_pending(&self) -> bool {
+        self.expected.is_some()
+    }
+
+    /// The pending request's response: the registers read, or the value written back. Until
+    /// it's complete (or with no request pending) that's `WouldBlock`, and after
+    /// `RESPONSE_TIMEOUT_MS` without one, `Timeout`.
+    pub fn poll(&mut self) -> nb::Result<Registers, Error> {
+        if self.expected.is_none() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        while let Ok(byte) = self.serial.read() {
+            if let Some(result) = self.feed(byte) {
+                self.expected = None;
+                return result.map_err(nb::Error::Other);
+            }
+        }
+
+        if self.clock.now_ms().wrapping_sub(self.started) > RESPONSE_TIMEOUT_MS {
+            self.expected = None;
+            Err(nb::Error::Other(Error::Timeout))
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    fn read_registers(
+        &mut self,
+        function: Function,
+        slave: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<(), Error> {
+        if count == 0 || count > MAX_REGISTERS {
+            return Err(Error::InvalidCount);
+        }
+        let byte_count = count as u8 * 2;
+        self.send(function, slave, address, count)?;
+        self.expect(
+            Vec::from_slice(&[slave, function as u8, byte_count]).unwrap(),
+            3 + byte_count as usize + 2,
+        );
+        Ok(())
+    }
+
+    // every request we make has the same layout: slave, function, two words and the CRC
+    fn send(
+        &mut self,
+        function: Function,
+        slave: u8,
+        address: u16,
+        value: u16,
+    ) -> Result<Frame, Error> {
+        // a late response to an earlier request would look just like the one to this request
+        while self.serial.read().is_ok() {}
+        self.expected = None;
+
+        let mut request = Frame::new();
+        request.extend_from_slice(&[slave, function as u8]).ok();
+        request.extend_from_slice(&address.to_be_bytes()).ok();
+        request.extend_from_slice(&value.to_be_bytes()).ok();
+        let crc = crc16(&request);
+        request.extend_from_slice(&crc.to_le_bytes()).ok();
+
+        self.set_direction(true)?;
+        let sent = self.write_all(&request);
+        // back to listening, whatever happened
+        self.set_direction(false)?;
+        sent.map(|_| request)
+    }
+
+    // `flush` has to wait until the last byte is out (TC on the STM32, not TXE), or the
+    // transceiver is switched back while it still goes out
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        for c in buf {
+            block!(self.serial.write(*c)).map_err(|_| Error::Serial)?;
+        }
+        block!(self.serial.flush()).map_err(|_| Error::Serial)
+    }
+
+    fn set_direction(&mut self, sending: bool) -> Result<(), Error> {
+        match self.direction.as_mut() {
+            Some(pin) if sending => pin.set_high(),
+            Some(pin) => pin.set_low(),
+            None => return Ok(()),
+        }
+        .map_err(|_| Error::DirectionPin)
+    }
+
+    fn expect(&mut self, header: Frame, len: usize) {
+        // not `clear`: heapless 0.5's `truncate` indexes past the end of the buffer
+        self.buf = Vec::new();
+        self.expected = Some(Expected { header, len });
+        self.started = self.clock.now_ms();
+    }
+
+    // returns the result once `byte` completes a valid response
+    fn feed(&mut self, byte: u8) -> Option<Result<Registers, Error>> {
+        // `Frame` fits the longest response, and the buffer never gets longer than the one
+        // expected: by then, it's either returned or skipped
+        self.buf.push(byte).ok();
+
+        loop {
+            let len = self.frame_len()?;
+            if self.buf.len() < len {
+                return None;
+            }
+
+            let (frame, crc) = self.buf[..len].split_at(len - 2);
+            if crc16(frame).to_le_bytes() == crc {
+                let result = if frame[1] & EXCEPTION_FLAG != 0 {
+                    Err(Error::Exception(Exception::from(frame[2])))
+                } else if frame[1] == Function::WriteSingleRegister as u8 {
+                    Ok(Vec::from_slice(&[u16::from_be_bytes([frame[4], frame[5]])]).unwrap())
+                } else {
+                    Ok(frame[3..]
+                        .chunks_exact(2)
+                        .map(|word| u16::from_be_bytes([word[0], word[1]]))
+                        .collect())
+                };
+                self.buf = Vec::new();
+                return Some(result);
+            }
+
+            // there may be a frame starting somewhere in this one
+            self.skip();
+        }
+    }
+
+    // how long the frame in the buffer is going to be, dropping bytes until it starts like the
+    // response (or an exception). `None` when the buffer is empty
+    fn frame_len(&mut self) -> Option<usize> {
+        loop {
+            let expected = self.expected.as_ref()?;
+            let header = &expected.header;
+            let exception = [header[0], header[1] | EXCEPTION_FLAG];
+
+            if self.buf.is_empty() {
+                return None;
+            } else if is_prefix(&self.buf, header) {
+                return Some(expected.len);
+            } else if is_prefix(&self.buf, &exception) {
+                // slave, function, exception code, CRC
+                return Some(5);
+            }
+            self.skip();
+        }
+    }
+
+    // the response doesn't start at the first byte in the buffer, look from the next one on
+    fn skip(&mut self) {
+        self.buf.rotate_left(1);
+        self.buf.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::{
+        cell::{Cell, RefCell},
+        collections::VecDeque,
+        rc::Rc,
+        vec::Vec as StdVec,
+    };
+
+    // records the direction pin's level for every byte written and for the flush
+    struct Serial {
+        pin: Rc<Cell<bool>>,
+        levels: Rc<Cell<(usize, usize, bool)>>,
+    }
+
+    impl Read<u8> for Serial {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    impl Write<u8> for Serial {
+        type Error = Infallible;
+
+        fn write(&mut self, _: u8) -> nb::Result<(), Infallible> {
+            let (written, high, flushed) = self.levels.get();
+            self.levels
+                .set((written + 1, high + self.pin.get() as usize, flushed));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            let (written, high, _) = self.levels.get();
+            self.levels.set((written, high, self.pin.get()));
+            Ok(())
+        }
+    }
+
+    struct Pin(Rc<Cell<bool>>);
+
+    impl OutputPin for Pin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.set(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.set(true);
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Bus {
+        // requests, as they went out
+        tx: StdVec<u8>,
+        // what the slave sent, not read yet
+        rx: VecDeque<u8>,
+        // what the slave answers to the next requests, in order
+        replies: VecDeque<StdVec<u8>>,
+    }
+
+    // the slave, at the other end of the bus: it answers once a request is flushed
+    #[derive(Clone, Default)]
+    struct Slave(Rc<RefCell<Bus>>);
+
+    impl Slave {
+        fn reply(&self, frame: &[u8]) {
+            self.0.borrow_mut().replies.push_back(frame.to_vec());
+        }
+
+        // whenever, not only in answer to a request
+        fn send(&self, bytes: &[u8]) {
+            self.0.borrow_mut().rx.extend(bytes);
+        }
+
+        fn requests(&self) -> StdVec<u8> {
+            std::mem::take(&mut self.0.borrow_mut().tx)
+        }
+    }
+
+    impl Read<u8> for Slave {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.0
+                .borrow_mut()
+                .rx
+                .pop_front()
+                .ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    impl Write<u8> for Slave {
+        type Error = Infallible;
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+            self.0.borrow_mut().tx.push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            let mut bus = self.0.borrow_mut();
+            if let Some(reply) = bus.replies.pop_front() {
+                bus.rx.extend(reply);
+            }
+            Ok(())
+        }
+    }
+
+    // only moves when it's told to
+    #[derive(Clone, Default)]
+    struct TestClock(Rc<Cell<u32>>);
+
+    impl Clock for TestClock {
+        fn now_ms(&self) -> u32 {
+            self.0.get()
+        }
+    }
+
+    fn master() -> (Master<Slave, TestClock>, Slave, TestClock) {
+        let slave = Slave::default();
+        let clock = TestClock::default();
+        (Master::new(slave.clone(), clock.clone()), slave, clock)
+    }
+
+    fn with_crc(frame: &[u8]) -> StdVec<u8> {
+        let mut frame = frame.to_vec();
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        // "read input registers" request from the S8's documentation
+        let request = [0xfe, 0x04, 0x00, 0x00, 0x00, 0x04];
+        assert_eq!(crc16(&request).to_le_bytes(), [0xe5, 0xc6]);
+    }
+
+    #[test]
+    fn direction_pin_is_high_while_sending() {
+        let pin = Rc::new(Cell::new(true));
+        let levels = Rc::new(Cell::new((0, 0, false)));
+        let serial = Serial {
+            pin: pin.clone(),
+            levels: levels.clone(),
+        };
+        let mut master = Master::new(serial, TestClock::default())
+            .direction_pin(Pin(pin.clone()))
+            .unwrap();
+        assert!(!pin.get());
+
+        master.read_input_registers(0xfe, 0, 4).unwrap();
+        // every byte went out with the transceiver sending, up to the flush
+        assert_eq!(levels.get(), (8, 8, true));
+        assert!(!pin.get());
+    }
+
+    #[test]
+    fn reads_registers() {
+        let (mut master, slave, _) = master();
+        slave.reply(&with_crc(&[0xfe, 0x04, 0x04, 0x00, 0x20, 0x01, 0x9a]));
+
+        master.read_input_registers(0xfe, 3, 2).unwrap();
+        assert_eq!(
+            slave.requests(),
+            with_crc(&[0xfe, 0x04, 0x00, 0x03, 0x00, 0x02])
+        );
+        assert_eq!(&master.poll().unwrap()[..], &[0x0020, 0x019a]);
+
+        // nothing's pending any more
+        assert!(!master.is_pending());
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+    }
+
+    #[test]
+    fn response_can_come_in_bits() {
+        let (mut master, slave, _) = master();
+        let response = with_crc(&[0x01, 0x03, 0x02, 0x00, 0xb4]);
+
+        master.read_holding_registers(0x01, 31, 1).unwrap();
+        for byte in &response[..response.len() - 1] {
+            slave.send(&[*byte]);
+            assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+        }
+        slave.send(&response[response.len() - 1..]);
+        assert_eq!(&master.poll().unwrap()[..], &[180]);
+    }
+
+    #[test]
+    fn exception_is_an_error() {
+        let (mut master, slave, _) = master();
+        slave.reply(&with_crc(&[0xfe, 0x84, 0x02]));
+
+        master.read_input_registers(0xfe, 0x100, 1).unwrap();
+        assert!(matches!(
+            master.poll(),
+            Err(nb::Error::Other(Error::Exception(
+                Exception::IllegalDataAddress
+            )))
+        ));
+        assert!(!master.is_pending());
+    }
+
+    #[test]
+    fn times_out() {
+        let (mut master, _, clock) = master();
+        // across the wrap-around
+        clock.0.set(u32::MAX - 10);
+
+        master.read_input_registers(0xfe, 0, 4).unwrap();
+        clock.0.set(RESPONSE_TIMEOUT_MS - 11);
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+        clock.0.set(RESPONSE_TIMEOUT_MS - 10);
+        assert!(matches!(
+            master.poll(),
+            Err(nb::Error::Other(Error::Timeout))
+        ));
+        assert!(!master.is_pending());
+    }
+
+    #[test]
+    fn resyncs_after_bad_crc() {
+        let (mut master, slave, _) = master();
+        let response = with_crc(&[0xfe, 0x04, 0x02, 0x01, 0xf4]);
+        let mut corrupted = response.clone();
+        corrupted[4] ^= 0x01;
+
+        master.read_input_registers(0xfe, 3, 1).unwrap();
+        // line noise, then a response which got garbled on the way
+        slave.send(&[0x00, 0xfe]);
+        slave.send(&corrupted);
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+
+        slave.send(&response);
+        assert_eq!(&master.poll().unwrap()[..], &[500]);
+    }
+
+    #[test]
+    fn write_is_echoed() {
+        let (mut master, slave, _) = master();
+        let request = with_crc(&[0xfe, 0x06, 0x00, 0x1f, 0x00, 0xb4]);
+
+        master.write_register(0xfe, 31, 180).unwrap();
+        assert_eq!(slave.requests(), request);
+
+        // only the request itself is an echo
+        slave.send(&with_crc(&[0xfe, 0x06, 0x00, 0x1f, 0x00, 0x00]));
+        assert!(matches!(master.poll(), Err(nb::Error::WouldBlock)));
+
+        slave.send(&request);
+        assert_eq!(&master.poll().unwrap()[..], &[180]);
+    }
+}
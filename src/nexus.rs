@@ -0,0 +1,154 @@
+// Nexus-TH temperature/humidity sensor protocol, which rtl_433 decodes out of the box
+// (as "Nexus-TH", with `temperature_C` and `humidity`).
+//
+// Every bit is a 500 us pulse followed by a 1000 us (0) or 2000 us (1) gap, and every row
+// ends with a pulse and a 4000 us gap. A message is 36 bits (9 nibbles), MSB first:
+//
+//  [id0] [id1] [flags] [temp0] [temp1] [temp2] [const] [humi0] [humi1]
+//
+// - flags are `B 0 C C`: battery OK, channel (0-2)
+// - temperature is 12 bits, signed, in 0.1 C
+// - const is always 0xf
+// - humidity is 8 bits, in % (0 means there's no humidity sensor)
+
+use crate::rf::{Error, Hertz, OokFrame};
+
+// everything is a multiple of 500 us
+const TICK_RATE: u32 = 2000;
+const PULSE_TICKS: usize = 1;
+const ZERO_GAP_TICKS: usize = 2;
+const ONE_GAP_TICKS: usize = 4;
+const SYNC_GAP_TICKS: usize = 8;
+
+const MESSAGE_BITS: usize = 36;
+// rtl_433 wants to see at least 3 identical rows
+const REPEATS: usize = 5;
+
+pub struct Reading {
+    pub id: u8,
+    /// 0-2 (shown as 1-3)
+    pub channel: u8,
+    pub battery_ok: bool,
+    /// in 0.01 C
+    pub temperature: i16,
+    /// in %
+    pub humidity: Option<u16>,
+}
+
+impl Reading {
+    fn to_bits(&self) -> u64 {
+        let flags = if self.battery_ok { 0x8 } else { 0x0 } | (self.channel & 0x3);
+        // round to the nearest 0.1 C, it has to fit in 12 bits
+        let temperature =
+            (self.temperature as i32 + if self.temperature < 0 { -5 } else { 5 }) / 10;
+        let temperature = temperature.clamp(-2048, 2047);
+        let humidity = self.humidity.map(|h| h.clamp(1, 99)).unwrap_or(0);
+
+        (self.id as u64) << 28
+            | (flags as u64) << 24
+            | ((temperature as u64) & 0xfff) << 12
+            | 0xf << 8
+            | humidity as u64
+    }
+}
+
+pub fn encode(reading: &Reading) -> Result<OokFrame, Error> {
+    let bits = reading.to_bits();
+    let mut frame = OokFrame::new(Hertz(TICK_RATE));
+
+    for _ in 0..REPEATS {
+        for n in (0..MESSAGE_BITS).rev() {
+            let gap = if (bits >> n) & 1 > 0 {
+                ONE_GAP_TICKS
+            } else {
+                ZERO_GAP_TICKS
+            };
+            frame.push(true, PULSE_TICKS)?;
+            frame.push(false, gap)?;
+        }
+        frame.push(true, PULSE_TICKS)?;
+        frame.push(false, SYNC_GAP_TICKS)?;
+    }
+
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec as StdVec;
+
+    fn reading(temperature: i16, humidity: Option<u16>) -> Reading {
+        Reading {
+            id: 0xa5,
+            channel: 1,
+            battery_ok: true,
+            temperature,
+            humidity,
+        }
+    }
+
+    // the rows sent, going by the timings rtl_433 expects
+    fn rows(frame: &OokFrame) -> StdVec<u64> {
+        // ticks of 500 us
+        assert_eq!(frame.bit_rate(), Hertz(2000));
+
+        let mut runs: StdVec<(bool, usize)> = StdVec::new();
+        for level in frame.levels() {
+            match runs.last_mut() {
+                Some((last, len)) if *last == level => *len += 1,
+                _ => runs.push((level, 1)),
+            }
+        }
+
+        let mut rows = StdVec::new();
+        let (mut row, mut num_bits) = (0, 0);
+        for pulse_gap in runs.chunks(2) {
+            assert_eq!(pulse_gap[0], (true, 1));
+            match pulse_gap[1] {
+                (false, 2) => row <<= 1,
+                (false, 4) => row = row << 1 | 1,
+                (false, 8) => {
+                    assert_eq!(num_bits, 36);
+                    rows.push(row);
+                    row = 0;
+                    num_bits = 0;
+                    continue;
+                }
+                gap => panic!("unexpected gap {:?}", gap),
+            }
+            num_bits += 1;
+        }
+        assert_eq!(num_bits, 0);
+        rows
+    }
+
+    fn bits(reading: &Reading) -> u64 {
+        let rows = rows(&encode(reading).unwrap());
+        assert_eq!(rows.len(), 5);
+        assert!(rows.iter().all(|row| *row == rows[0]));
+        rows[0]
+    }
+
+    #[test]
+    fn encodes_rows() {
+        // id a5, battery OK and channel 1, 21.4 C, const f, 45 %
+        assert_eq!(bits(&reading(2137, Some(45))), 0xa590d6f2d);
+        // -12.3 C, and no humidity sensor
+        assert_eq!(bits(&reading(-1234, None)), 0xa59f85f00);
+    }
+
+    #[test]
+    fn rounds_and_clamps() {
+        let temperature = |t| (bits(&reading(t, Some(45))) >> 12) & 0xfff;
+        assert_eq!(temperature(2134), 213);
+        assert_eq!(temperature(2135), 214);
+        assert_eq!(temperature(-1235), 0x1000 - 124);
+        assert_eq!(temperature(i16::MAX), 0x7ff);
+        assert_eq!(temperature(i16::MIN), 0x800);
+
+        let humidity = |h| bits(&reading(2000, Some(h))) & 0xff;
+        assert_eq!(humidity(0), 1);
+        assert_eq!(humidity(120), 99);
+    }
+}
@@ -0,0 +1,39 @@
+use core::ptr;
+
+// 96-bit unique device ID (RM0368, section 24.2)
+const UNIQUE_ID: *const [u8; 12] = 0x1fff_7a10 as *const _;
+
+/// RadioHead's broadcast address
+pub const BROADCAST: u8 = 0xff;
+
+pub fn unique_id() -> [u8; 12] {
+    // it's a read-only area which is always there
+    unsafe { ptr::read_volatile(UNIQUE_ID) }
+}
+
+// 32-bit FNV-1a
+fn hash_unique_id(uid: &[u8; 12]) -> u32 {
+    uid.iter().fold(0x811c_9dc5_u32, |h, b| {
+        (h ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Squash the unique ID into an 8-bit node address (never the broadcast one).
+pub fn address_from_unique_id(uid: &[u8; 12]) -> u8 {
+    // the hash, folded down to 8 bits
+    let address = hash_unique_id(uid)
+        .to_le_bytes()
+        .iter()
+        .fold(0, |a, b| a ^ b);
+
+    if address == BROADCAST {
+        BROADCAST - 1
+    } else {
+        address
+    }
+}
+
+/// Seed for random numbers, which is different on every node.
+pub fn seed_from_unique_id(uid: &[u8; 12]) -> u32 {
+    hash_unique_id(uid)
+}
@@ -0,0 +1,129 @@
+// Counter which never goes back, not even across resets and power cycles (used by `auth`).
+// Its upper 16 bits ("epoch") are the number of words programmed in the last flash sector, which
+// `memory.x` leaves out. The epoch goes up on every boot and whenever the lower bits wrap.
+//
+// The sector is never erased, since losing power between erasing and programming would take the
+// epoch back to 0. Once all its words are used up (after 32768 epochs, decades of normal use),
+// the counter stops for good: the node then needs a new key, and the sector erasing.
+// It also stops when programming a word fails.
+
+use core::ptr;
+use stm32f4xx_hal::stm32::FLASH;
+
+const SECTOR_START: *mut u32 = 0x0806_0000 as *mut _;
+const SECTOR_WORDS: usize = 128 * 1024 / 4;
+// so that every epoch fits in 16 bits
+const _: () = assert!(SECTOR_WORDS <= 0x1_0000);
+
+const ERASED: u32 = 0xffff_ffff;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xcdef_89ab;
+const PSIZE_X32: u8 = 0b10;
+
+fn read_word(index: usize) -> u32 {
+    unsafe { ptr::read_volatile(SECTOR_START.add(index)) }
+}
+
+fn wait_ready(flash: &FLASH) {
+    while flash.sr.read().bsy().bit_is_set() {}
+}
+
+fn unlock(flash: &FLASH) {
+    if flash.cr.read().lock().bit_is_set() {
+        flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+        flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+    }
+}
+
+fn lock(flash: &FLASH) {
+    flash.cr.modify(|_, w| w.lock().set_bit());
+}
+
+// Error flags left over from earlier would make programming fail straight away.
+fn clear_errors(flash: &FLASH) {
+    // they're cleared by writing 1s
+    flash.sr.write(|w| {
+        w.operr()
+            .set_bit()
+            .wrperr()
+            .set_bit()
+            .pgaerr()
+            .set_bit()
+            .pgperr()
+            .set_bit()
+            .pgserr()
+            .set_bit()
+    });
+}
+
+fn has_errors(flash: &FLASH) -> bool {
+    let sr = flash.sr.read();
+    sr.operr().bit_is_set()
+        || sr.wrperr().bit_is_set()
+        || sr.pgaerr().bit_is_set()
+        || sr.pgperr().bit_is_set()
+        || sr.pgserr().bit_is_set()
+}
+
+fn program_word(flash: &FLASH, index: usize, value: u32) -> Result<(), Error> {
+    wait_ready(flash);
+    clear_errors(flash);
+    flash
+        .cr
+        .modify(|_, w| unsafe { w.psize().bits(PSIZE_X32).pg().set_bit() });
+    unsafe { ptr::write_volatile(SECTOR_START.add(index), value) };
+    wait_ready(flash);
+    flash.cr.modify(|_, w| w.pg().clear_bit());
+
+    if has_errors(flash) || read_word(index) != value {
+        return Err(Error::Flash);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error {
+    /// The counter has run out, see above.
+    Exhausted,
+    /// Programming the next epoch failed. It can't be told apart from the last one after a reset,
+    /// so the counter stops.
+    Flash,
+}
+
+// Increment the epoch and return its new value.
+fn increment_epoch(flash: &mut FLASH) -> Result<u32, Error> {
+    // a word whose programming was cut short isn't erased any more, so it's never reused
+    let index = (0..SECTOR_WORDS)
+        .find(|i| read_word(*i) == ERASED)
+        .ok_or(Error::Exhausted)?;
+
+    unlock(flash);
+    let result = program_word(flash, index, 0);
+    lock(flash);
+
+    result.map(|_| index as u32)
+}
+
+pub struct MonotonicCounter {
+    flash: FLASH,
+    // an error once it has stopped
+    value: Result<u32, Error>,
+}
+
+impl MonotonicCounter {
+    pub fn new(mut flash: FLASH) -> Self {
+        let value = increment_epoch(&mut flash).map(|epoch| epoch << 16);
+        Self { flash, value }
+    }
+
+    pub fn increment(&mut self) -> Result<u32, Error> {
+        let value = self.value?.wrapping_add(1);
+        self.value = if value & 0xffff == 0 {
+            increment_epoch(&mut self.flash).map(|epoch| epoch << 16)
+        } else {
+            Ok(value)
+        };
+        self.value
+    }
+}
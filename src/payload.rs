@@ -0,0 +1,275 @@
+// Sensor payload, sent as a single RadioHead packet (all values little-endian):
+//
+//  0     version
+//  1     sequence number
+//  2     flags (bit 0: temperature valid, bit 1: humidity valid, bit 2: CO2 valid,
+//        bit 3: CO2 sensor warming up)
+//  3-4   temperature, in 0.01 C (signed)
+//  5-6   humidity, in %
+//  7-8   CO2, in ppm
+//
+// Values which aren't valid are sent as 0.
+//
+// Version 2 is the same, followed by a summary of every reading taken since the last payload,
+// for temperature, humidity and CO2 (in that order, same units as above):
+//
+//  0-1   minimum
+//  2-3   maximum
+//  4-5   mean
+//  6     number of readings (0 if there weren't any, in which case the rest is 0 too)
+
+use heapless::{consts::*, Vec};
+
+use crate::wire::{self, Decode, Encode};
+
+pub const VERSION: u8 = 1;
+pub const PAYLOAD_LEN: usize = 9;
+
+pub const VERSION_EXTENDED: u8 = 2;
+pub const EXTENDED_PAYLOAD_LEN: usize = PAYLOAD_LEN + 3 * SUMMARY_LEN;
+
+const SUMMARY_LEN: usize = 7;
+
+const FLAG_TEMPERATURE: u8 = 0x01;
+const FLAG_HUMIDITY: u8 = 0x02;
+const FLAG_CO2: u8 = 0x04;
+const FLAG_CO2_WARMING_UP: u8 = 0x08;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    TooShort,
+    UnsupportedVersion(u8),
+}
+
+impl From<wire::Error> for Error {
+    fn from(err: wire::Error) -> Self {
+        match err {
+            wire::Error::TooShort => Error::TooShort,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary<T> {
+    pub min: T,
+    pub max: T,
+    pub mean: T,
+    pub count: u8,
+}
+
+impl<T: Encode + Default> Summary<T> {
+    fn encode(summary: &Option<Self>, buf: &mut [u8]) {
+        let zero = Summary {
+            min: T::default(),
+            max: T::default(),
+            mean: T::default(),
+            count: 0,
+        };
+        let summary = summary.as_ref().unwrap_or(&zero);
+        summary.min.encode(&mut buf[0..2]);
+        summary.max.encode(&mut buf[2..4]);
+        summary.mean.encode(&mut buf[4..6]);
+        buf[6] = summary.count;
+    }
+}
+
+impl<T: Decode> Summary<T> {
+    fn decode(buf: &[u8]) -> Result<Option<Self>, Error> {
+        let summary = Summary {
+            min: T::decode(&buf[0..])?,
+            max: T::decode(&buf[2..])?,
+            mean: T::decode(&buf[4..])?,
+            count: u8::decode(&buf[6..])?,
+        };
+        Ok(Some(summary).filter(|s| s.count > 0))
+    }
+}
+
+/// Readings since the last payload, for the extended payload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summaries {
+    pub temperature: Option<Summary<i16>>,
+    pub humidity: Option<Summary<u16>>,
+    pub co2: Option<Summary<u16>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Payload {
+    pub sequence: u8,
+    pub temperature: Option<i16>,
+    pub humidity: Option<u16>,
+    pub co2: Option<u16>,
+    /// there's no CO2 reading yet because the sensor is warming up
+    pub co2_warming_up: bool,
+    /// sent (as version 2) if it's there
+    pub summaries: Option<Summaries>,
+}
+
+impl Payload {
+    pub fn encode(&self) -> Vec<u8, U32> {
+        let mut flags = 0;
+        if self.temperature.is_some() {
+            flags |= FLAG_TEMPERATURE;
+        }
+        if self.humidity.is_some() {
+            flags |= FLAG_HUMIDITY;
+        }
+        if self.co2.is_some() {
+            flags |= FLAG_CO2;
+        }
+        if self.co2_warming_up {
+            flags |= FLAG_CO2_WARMING_UP;
+        }
+
+        let (version, len) = match self.summaries {
+            Some(_) => (VERSION_EXTENDED, EXTENDED_PAYLOAD_LEN),
+            None => (VERSION, PAYLOAD_LEN),
+        };
+
+        let mut buf = Vec::new();
+        // can't fail, the buffer is big enough
+        buf.resize_default(len).unwrap();
+        buf[0] = version;
+        buf[1] = self.sequence;
+        buf[2] = flags;
+        self.temperature.unwrap_or(0).encode(&mut buf[3..5]);
+        self.humidity.unwrap_or(0).encode(&mut buf[5..7]);
+        self.co2.unwrap_or(0).encode(&mut buf[7..9]);
+
+        if let Some(summaries) = self.summaries {
+            let mut summary_buf = buf[PAYLOAD_LEN..].chunks_exact_mut(SUMMARY_LEN);
+            // there's exactly one chunk for each of them
+            Summary::encode(&summaries.temperature, summary_buf.next().unwrap());
+            Summary::encode(&summaries.humidity, summary_buf.next().unwrap());
+            Summary::encode(&summaries.co2, summary_buf.next().unwrap());
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let version = *buf.first().ok_or(Error::TooShort)?;
+        let len = match version {
+            VERSION => PAYLOAD_LEN,
+            VERSION_EXTENDED => EXTENDED_PAYLOAD_LEN,
+            v => return Err(Error::UnsupportedVersion(v)),
+        };
+        if buf.len() < len {
+            return Err(Error::TooShort);
+        }
+
+        let flags = buf[2];
+        let valid = |flag: u8| flags & flag > 0;
+
+        let summaries = if version == VERSION_EXTENDED {
+            let summary = |n: usize| &buf[PAYLOAD_LEN + n * SUMMARY_LEN..];
+            Some(Summaries {
+                temperature: Summary::decode(summary(0))?,
+                humidity: Summary::decode(summary(1))?,
+                co2: Summary::decode(summary(2))?,
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            sequence: buf[1],
+            temperature: Some(i16::decode(&buf[3..])?).filter(|_| valid(FLAG_TEMPERATURE)),
+            humidity: Some(u16::decode(&buf[5..])?).filter(|_| valid(FLAG_HUMIDITY)),
+            co2: Some(u16::decode(&buf[7..])?).filter(|_| valid(FLAG_CO2)),
+            co2_warming_up: valid(FLAG_CO2_WARMING_UP),
+            summaries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: Payload = Payload {
+        sequence: 0x2a,
+        temperature: Some(-1234),
+        humidity: Some(45),
+        co2: None,
+        co2_warming_up: true,
+        summaries: None,
+    };
+
+    const SUMMARIES: Summaries = Summaries {
+        temperature: Some(Summary {
+            min: -1300,
+            max: 2150,
+            mean: 512,
+            count: 12,
+        }),
+        humidity: None,
+        co2: Some(Summary {
+            min: 400,
+            max: 1200,
+            mean: 800,
+            count: 3,
+        }),
+    };
+
+    #[test]
+    fn encode_v1() {
+        assert_eq!(
+            &PAYLOAD.encode()[..],
+            &[1, 0x2a, 0x0b, 0x2e, 0xfb, 45, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn encode_v2() {
+        let payload = Payload {
+            summaries: Some(SUMMARIES),
+            ..PAYLOAD
+        };
+        #[rustfmt::skip]
+        let expected = [
+            2, 0x2a, 0x0b, 0x2e, 0xfb, 45, 0, 0, 0,
+            // temperature
+            0xec, 0xfa, 0x66, 0x08, 0x00, 0x02, 12,
+            // humidity
+            0, 0, 0, 0, 0, 0, 0,
+            // CO2
+            0x90, 0x01, 0xb0, 0x04, 0x20, 0x03, 3,
+        ];
+        assert_eq!(&payload.encode()[..], &expected[..]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let payloads = [
+            PAYLOAD,
+            Payload {
+                temperature: None,
+                humidity: None,
+                co2: Some(5000),
+                co2_warming_up: false,
+                ..PAYLOAD
+            },
+            Payload {
+                summaries: Some(SUMMARIES),
+                ..PAYLOAD
+            },
+        ];
+
+        for payload in payloads.iter() {
+            assert_eq!(Payload::decode(&payload.encode()), Ok(*payload));
+        }
+    }
+
+    #[test]
+    fn decode_errors() {
+        let buf = Payload {
+            summaries: Some(SUMMARIES),
+            ..PAYLOAD
+        }
+        .encode();
+
+        assert_eq!(Payload::decode(&[]), Err(Error::TooShort));
+        assert_eq!(Payload::decode(&buf[..PAYLOAD_LEN]), Err(Error::TooShort));
+        assert_eq!(Payload::decode(&[3; 9]), Err(Error::UnsupportedVersion(3)));
+    }
+}
@@ -1,10 +1,11 @@
+use embedded_hal::timer::{Cancel, CountDown, Periodic};
 use shared_bus::{I2cProxy, NullMutex};
 use ssd1306::{displaysize::DisplaySize128x32, prelude::*, Builder, I2CDIBuilder};
 use stm32f4xx_hal::{
     gpio::{
-        gpioa::{self, PA10, PA6, PA7, PA9},
+        gpioa::{self, PA0, PA10, PA6, PA7, PA9},
         gpiob::{PB8, PB9},
-        Alternate, AlternateOD, OpenDrain, Output, PushPull, AF4, AF7,
+        Alternate, AlternateOD, Input, OpenDrain, Output, PullUp, PushPull, AF4, AF7,
     },
     i2c::I2c,
     prelude::*,
@@ -14,19 +15,58 @@ use stm32f4xx_hal::{
         config::{Parity, StopBits, WordLength},
         Event as SerialEvent, Serial,
     },
-    stm32::{I2C1, TIM1, USART1},
-    timer::Timer,
+    stm32::{GPIOA, I2C1, TIM1, USART1},
+    timer::{self, Event as TimerEvent, Timer},
 };
 
-use crate::radiohead_ask;
+#[cfg(any(feature = "scd4x", feature = "scd30", feature = "senseair-s8"))]
+use clima_sensors::co2::Co2Sensor;
+#[cfg(not(any(feature = "scd4x", feature = "scd30", feature = "senseair-s8")))]
+use clima_sensors::mhz19b;
+#[cfg(feature = "senseair-s8")]
+use clima_sensors::senseair;
+#[cfg(any(feature = "scd4x", feature = "scd30"))]
+use clima_sensors::sensirion;
+use clima_sensors::{radiohead_ask, rf};
+
+use crate::syscalls;
 
 type I2CInterfaceProxy<'t> =
     I2cProxy<'t, NullMutex<I2c<I2C1, (PB8<AlternateOD<AF4>>, PB9<AlternateOD<AF4>>)>>>;
 type MLX90614<'t> = mlx9061x::Mlx9061x<I2CInterfaceProxy<'t>, mlx9061x::ic::Mlx90614>;
 type DHT11 = dht11::Dht11<PA6<Output<OpenDrain>>>;
 type UARTPins = (PA9<Alternate<AF7>>, PA10<Alternate<AF7>>);
-pub type RadioHeadASK = radiohead_ask::RadioHeadASK<PA7<Output<PushPull>>, Timer<TIM1>>;
+pub type RadioHeadASK = radiohead_ask::RadioHeadASK<PA7<Output<PushPull>>, RadioTimer>;
+// the Black Pill's KEY button, which pulls it low
+pub type KeyButton = PA0<Input<PullUp>>;
+
+/// TIM1, counting in `rf::Hertz` (the radio code doesn't know about the HAL).
+pub struct RadioTimer(Timer<TIM1>);
+
+impl CountDown for RadioTimer {
+    type Time = rf::Hertz;
+
+    fn start<T>(&mut self, timeout: T)
+    where
+        T: Into<rf::Hertz>,
+    {
+        self.0.start(timeout.into().0.hz());
+    }
+
+    fn wait(&mut self) -> nb::Result<(), void::Void> {
+        self.0.wait()
+    }
+}
 
+impl Periodic for RadioTimer {}
+
+impl Cancel for RadioTimer {
+    type Error = timer::Error;
+
+    fn cancel(&mut self) -> Result<(), Self::Error> {
+        self.0.cancel()
+    }
+}
 
 pub fn setup_display<I>(i2c: I) -> GraphicsMode<I2CInterface<I>, DisplaySize128x32>
 where
@@ -48,8 +88,14 @@ pub fn setup(
     i2c: I2CInterfaceProxy,
     clocks: Clocks,
     tim1: TIM1,
-    usart1: USART1
-) -> (MLX90614, DHT11, RadioHeadASK, Serial<USART1, UARTPins>) {
+    usart1: USART1,
+) -> (
+    MLX90614,
+    DHT11,
+    RadioHeadASK,
+    Serial<USART1, UARTPins>,
+    KeyButton,
+) {
     let humidity_sensor: DHT11 = dht11::Dht11::new(gpioa.pa6.into_open_drain_output());
     let temperature_sensor =
         mlx9061x::Mlx9061x::new_mlx90614(i2c, mlx9061x::SlaveAddr::Alternative(0x5a), 5).unwrap();
@@ -60,7 +106,9 @@ pub fn setup(
         stopbits: StopBits::STOP1,
     };
 
-    let timer = Timer::tim1(tim1, 200.khz(), clocks);
+    let mut timer = Timer::tim1(tim1, 200.khz(), clocks);
+    // the radio is clocked from the TIM1 update interrupt
+    timer.listen(TimerEvent::TimeOut);
 
     let mut uart = serial::Serial::usart1(
         usart1,
@@ -74,7 +122,59 @@ pub fn setup(
     .unwrap();
     uart.listen(SerialEvent::Rxne);
 
-    let radio = radiohead_ask::RadioHeadASK::new(gpioa.pa7.into_push_pull_output(), timer);
+    let radio = radiohead_ask::RadioHeadASK::new(
+        gpioa.pa7.into_push_pull_output(),
+        RadioTimer(timer),
+        radiohead_ask::Config::default().fec(cfg!(feature = "fec")),
+    )
+    .unwrap();
+
+    let key = gpioa.pa0.into_pull_up_input();
+
+    (temperature_sensor, humidity_sensor, radio, uart, key)
+}
+
+/// Switch the transmitter off, whatever the radio was doing, e.g. from the panic handler.
+pub fn radio_off() {
+    // the radio's pin is PA7, and BSRR only touches the pins whose bits are set
+    unsafe { (*GPIOA::ptr()).bsrr.write(|w| w.br7().set_bit()) };
+}
+
+#[cfg(not(any(feature = "scd4x", feature = "scd30", feature = "senseair-s8")))]
+pub type Mhz19b = mhz19b::Mhz19b<syscalls::BufferedUart<serial::Tx<USART1>>, syscalls::SystemClock>;
+
+/// The MH-Z19B on `uart`, or with the `scd4x`/`scd30` features, a Sensirion sensor on `i2c`,
+/// or with `senseair-s8`, an S8 on `uart`.
+#[cfg(not(any(feature = "scd4x", feature = "scd30", feature = "senseair-s8")))]
+pub fn setup_co2_sensor(uart: Serial<USART1, UARTPins>, _i2c: I2CInterfaceProxy) -> Mhz19b {
+    // what the sensor sends back is picked up by the USART1 interrupt
+    let (tx, _) = uart.split();
+    mhz19b::Mhz19b::new(syscalls::BufferedUart::new(tx), syscalls::SystemClock)
+        .detection_range(crate::CO2_DETECTION_RANGE)
+}
+
+#[cfg(feature = "scd4x")]
+pub fn setup_co2_sensor<'t>(
+    _uart: Serial<USART1, UARTPins>,
+    i2c: I2CInterfaceProxy<'t>,
+) -> impl Co2Sensor + 't {
+    sensirion::Scd::scd4x(i2c, syscalls::SystemClock)
+}
+
+#[cfg(feature = "scd30")]
+pub fn setup_co2_sensor<'t>(
+    _uart: Serial<USART1, UARTPins>,
+    i2c: I2CInterfaceProxy<'t>,
+) -> impl Co2Sensor + 't {
+    sensirion::Scd::scd30(i2c, syscalls::SystemClock)
+}
 
-    (temperature_sensor, humidity_sensor, radio, uart)
+#[cfg(feature = "senseair-s8")]
+pub fn setup_co2_sensor<'t>(
+    uart: Serial<USART1, UARTPins>,
+    _i2c: I2CInterfaceProxy<'t>,
+) -> impl Co2Sensor + 't {
+    // what the sensor sends back is picked up by the USART1 interrupt
+    let (tx, _) = uart.split();
+    senseair::S8::new(syscalls::BufferedUart::new(tx), syscalls::SystemClock)
 }
@@ -0,0 +1,20 @@
+// Pins which are optional on the board.
+
+use core::convert::Infallible;
+use embedded_hal::digital::v2::OutputPin;
+
+/// Stand-in for an output pin which isn't there, e.g. a transmitter's PTT or an RS-485
+/// transceiver's direction. Setting it does nothing.
+pub struct NoPin;
+
+impl OutputPin for NoPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -1,57 +1,310 @@
+use core::{cmp, convert::TryInto, mem};
 use embedded_hal::{
-    digital::v2::OutputPin,
-    timer::{CountDown, Periodic},
+    digital::v2::{InputPin, OutputPin},
+    timer::{Cancel, CountDown, Periodic},
+};
+use heapless::{consts::*, spsc::Queue, Vec};
+
+use crate::{
+    fec,
+    pin::NoPin,
+    rf::{self, Error, Header, Hertz, OokFrame, RfEncoder},
 };
-use nb::block;
-use stm32f4xx_hal::{prelude::*, time::Hertz};
 
 const SYMBOLS: [u8; 16] = [
     0xd, 0xe, 0x13, 0x15, 0x16, 0x19, 0x1a, 0x1c, 0x23, 0x25, 0x26, 0x29, 0x2a, 0x2c, 0x32, 0x34,
 ];
 
-#[derive(Debug)]
-pub enum Error {
-    Port,
+const MIN_BIT_RATE: u32 = 100;
+const MAX_BIT_RATE: u32 = 10_000;
+const MAX_PREAMBLE_LEN: u8 = 32;
+
+/// Test transmissions (see `RadioHeadASK::start_test`) are cut off after this long, in ms.
+pub const MAX_TEST_DURATION_MS: u32 = 60_000;
+
+// how often the timeout is checked while sending a carrier
+const CARRIER_TICK_RATE: u32 = 1000;
+
+/// Maximum size of the content of a packet (`RH_ASK_MAX_MESSAGE_LEN`)
+pub const MAX_MESSAGE_LEN: usize = 60;
+
+// count + header (4 bytes) + message + FCS (2 bytes), as in RadioHead
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN + 7;
+
+// the same, with FEC
+const MAX_CODED_LEN: usize = fec::coded_len(MAX_PAYLOAD_LEN);
+
+// preamble + start symbol + every byte as two sextets
+const BUFFER_BITS: usize = (MAX_PREAMBLE_LEN as usize + 2 + MAX_CODED_LEN * 2) * 6;
+const _: () = assert!(
+    BUFFER_BITS <= rf::MAX_FRAME_BITS,
+    "frames don't fit in an OokFrame"
+);
+
+// the receiver's PLL works like RadioHead's: a ramp which wraps around once per bit
+// and gets retarded/advanced whenever there's a transition
+const RX_SAMPLES_PER_BIT: u8 = 8;
+const RX_RAMP_LEN: u8 = 160;
+const RAMP_INC: u8 = RX_RAMP_LEN / RX_SAMPLES_PER_BIT;
+const RAMP_TRANSITION: u8 = RX_RAMP_LEN / 2;
+const RAMP_ADJUST: u8 = 9;
+const RAMP_INC_RETARD: u8 = RAMP_INC - RAMP_ADJUST;
+const RAMP_INC_ADVANCE: u8 = RAMP_INC + RAMP_ADJUST;
+
+// 0x38, 0x2c, in the order they're received
+const START_SYMBOL: u16 = 0xb38;
+
+// the CRC over a whole valid message (FCS included) always ends up being this
+const FCS_RESIDUE: u16 = 0xf0b8;
+
+#[derive(Debug, PartialEq)]
+pub struct Packet {
+    pub from: u8,
+    pub to: u8,
+    pub id: u8,
+    pub flags: u8,
+    pub payload: Vec<u8, U60>, // MAX_MESSAGE_LEN
+}
+
+fn update_fcs(fcs: u16, data: u8) -> u16 {
+    let mut new_data = data ^ (fcs as u8);
+    new_data ^= new_data << 4;
+    (((new_data as u16) << 8) | (fcs >> 8)) ^ ((new_data >> 4) as u16) ^ ((new_data as u16) << 3)
+}
+
+fn symbol_6to4(symbol: u8) -> Option<u8> {
+    SYMBOLS
+        .iter()
+        .position(|s| *s == symbol)
+        .map(|nibble| nibble as u8)
+}
+
+// the symbol with the fewest differing bits (the first one, on a tie)
+fn nearest_symbol_6to4(symbol: u8) -> u8 {
+    SYMBOLS
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, s)| (*s ^ symbol).count_ones())
+        .map_or(0, |(nibble, _)| nibble as u8)
+}
+
+fn push_sextet(frame: &mut OokFrame, sextet: u8) -> Result<(), Error> {
+    for i in 0..6 {
+        frame.push((sextet & (0x01 << i)) > 0, 1)?;
+    }
+    Ok(())
+}
+
+fn push_bytes(frame: &mut OokFrame, bytes: &[u8]) -> Result<(), Error> {
+    for b in bytes {
+        push_sextet(frame, SYMBOLS[(b >> 4) as usize])?;
+        push_sextet(frame, SYMBOLS[(b & 0xf) as usize])?;
+    }
+    Ok(())
+}
+
+/// Encode a packet into the levels that go on the air.
+pub fn encode<E>(config: &Config<E>, header: &Header, content: &[u8]) -> Result<OokFrame, Error>
+where
+    E: OutputPin,
+{
+    if content.len() > MAX_MESSAGE_LEN {
+        return Err(Error::PayloadTooLong);
+    }
+
+    let msg_len = content.len() + 7;
+    // MAX_PAYLOAD_LEN bytes at most, the length was checked above.
+    // RadioHead puts the destination first
+    let mut message = Vec::<u8, U67>::new();
+    message
+        .extend_from_slice(&[
+            msg_len as u8,
+            header.to,
+            header.from,
+            header.id,
+            header.flags,
+        ])
+        .unwrap();
+    message.extend_from_slice(content).unwrap();
+    let crc = !message.iter().fold(0xffff, |fcs, b| update_fcs(fcs, *b));
+    message.extend_from_slice(&crc.to_le_bytes()).unwrap();
+
+    let mut frame = OokFrame::new(config.bit_rate);
+
+    for _i in 0..config.preamble_len {
+        push_sextet(&mut frame, 0x2a)?;
+    }
+    push_sextet(&mut frame, 0x38)?;
+    push_sextet(&mut frame, 0x2c)?;
+
+    if config.fec {
+        for block in message.chunks(fec::BLOCK_LEN) {
+            push_bytes(&mut frame, &fec::encode_block(block))?;
+        }
+    } else {
+        push_bytes(&mut frame, &message)?;
+    }
+
+    Ok(frame)
+}
+
+/// Number of bits in a packet with `content_len` bytes of content.
+pub fn frame_bits<E>(config: &Config<E>, content_len: usize) -> usize
+where
+    E: OutputPin,
+{
+    let msg_len = content_len + 7;
+    let num_bytes = if config.fec {
+        fec::coded_len(msg_len)
+    } else {
+        msg_len
+    };
+    (config.preamble_len as usize + 2 + num_bytes * 2) * 6
+}
+
+pub struct Config<E = NoPin>
+where
+    E: OutputPin,
+{
+    pub bit_rate: Hertz,
+    /// number of 0x2a sextets sent before the start symbol
+    pub preamble_len: u8,
+    /// output is active-low
+    pub inverted: bool,
+    /// pin which is set high while transmitting
+    pub ptt: Option<E>,
+    /// error correction (see `fec`), which the receiver has to use too.
+    /// It's not something RadioHead understands, and frames take twice as long.
+    pub fec: bool,
+}
+
+impl Default for Config<NoPin> {
+    fn default() -> Self {
+        Self {
+            bit_rate: Hertz(2000),
+            preamble_len: 6,
+            inverted: false,
+            ptt: None,
+            fec: false,
+        }
+    }
+}
+
+impl<E> Config<E>
+where
+    E: OutputPin,
+{
+    pub fn bit_rate(mut self, bit_rate: Hertz) -> Self {
+        self.bit_rate = bit_rate;
+        self
+    }
+
+    pub fn preamble_len(mut self, preamble_len: u8) -> Self {
+        self.preamble_len = preamble_len;
+        self
+    }
+
+    pub fn inverted(mut self, inverted: bool) -> Self {
+        self.inverted = inverted;
+        self
+    }
+
+    pub fn fec(mut self, fec: bool) -> Self {
+        self.fec = fec;
+        self
+    }
+
+    pub fn ptt<F>(self, ptt: F) -> Config<F>
+    where
+        F: OutputPin,
+    {
+        Config {
+            bit_rate: self.bit_rate,
+            preamble_len: self.preamble_len,
+            inverted: self.inverted,
+            ptt: Some(ptt),
+            fec: self.fec,
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.bit_rate.0 < MIN_BIT_RATE || self.bit_rate.0 > MAX_BIT_RATE {
+            Err(Error::InvalidBitRate)
+        } else if self.preamble_len == 0 || self.preamble_len > MAX_PREAMBLE_LEN {
+            Err(Error::InvalidPreambleLength)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Signals for tuning the antenna.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestSignal {
+    /// the output is kept on
+    Carrier,
+    /// on/off at this frequency, 50% duty cycle (up to half the maximum bit rate)
+    SquareWave(Hertz),
 }
 
-pub struct RadioHeadASK<P, T>
+// a test transmission in progress
+struct TestState {
+    ticks_left: u32,
+    square: bool,
+    level: bool,
+}
+
+/// RadioHead ASK transmitter.
+///
+/// `send_packet` only encodes the frame and puts it in a small queue; the bits are
+/// then clocked out one by one from the timer interrupt, through `tick`.
+pub struct RadioHeadASK<P, T, E = NoPin>
 where
     P: OutputPin,
-    T: CountDown + Periodic,
+    T: CountDown + Periodic + Cancel,
+    E: OutputPin,
 {
     pin: P,
     timer: T,
-    fcs: u16,
-    buffer: [u8; 128],
-    buffer_ptr: usize,
+    config: Config<E>,
+    queue: Queue<OokFrame, U4>,
+    bit_ptr: usize,
+    busy: bool,
+    tx_done: bool,
+    fault: Option<Error>,
+    test: Option<TestState>,
 }
 
-impl<P, T> RadioHeadASK<P, T>
+impl<P, T, E> RadioHeadASK<P, T, E>
 where
     P: OutputPin,
-    T: CountDown<Time = Hertz> + Periodic,
+    T: CountDown<Time = Hertz> + Periodic + Cancel,
+    E: OutputPin,
 {
-    pub fn new(mut pin: P, timer: T) -> Self {
-        match pin.set_low() {
-            Ok(_) => {}
-            Err(_) => panic!("Couldn't set pin to low!"),
-        }
+    /// `timer` should already be set to fire an interrupt on timeout, and that
+    /// interrupt should call `tick`.
+    pub fn new(pin: P, mut timer: T, config: Config<E>) -> Result<Self, Error> {
+        config.validate()?;
 
-        Self {
+        // we only want the timer running while there's something to send
+        timer.cancel().ok();
+
+        let mut radio = Self {
             pin,
             timer,
-            fcs: 0xffff,
-            buffer: [0_u8; 128],
-            buffer_ptr: 0,
-        }
-    }
+            config,
+            queue: Queue::new(),
+            bit_ptr: 0,
+            busy: false,
+            tx_done: false,
+            fault: None,
+            test: None,
+        };
+
+        radio.set_bit(false)?;
+        radio.set_ptt(false)?;
 
-    fn update_fcs(&mut self, data: u8) {
-        let mut new_data = data ^ (self.fcs as u8);
-        new_data ^= new_data << 4;
-        self.fcs = (((new_data as u16) << 8) | (self.fcs >> 8))
-            ^ ((new_data >> 4) as u16)
-            ^ ((new_data as u16) << 3);
+        Ok(radio)
     }
 
     pub fn send_packet(
@@ -62,62 +315,656 @@ where
         header_flags: u8,
         content: &[u8],
     ) -> Result<(), Error> {
-        self.buffer_ptr = 0;
+        let header = Header {
+            from,
+            to,
+            id,
+            flags: header_flags,
+        };
+        let frame = encode(&self.config, &header, content)?;
+        self.send_ook(frame)
+    }
+
+    /// Send raw levels, e.g. for other protocols which share the transmitter.
+    pub fn send_ook(&mut self, frame: OokFrame) -> Result<(), Error> {
+        // anything that went wrong in the meantime, in the interrupt
+        if let Some(err) = self.fault.take() {
+            return Err(err);
+        }
 
-        self.fcs = 0xffff;
+        self.queue.enqueue(frame).map_err(|_| Error::Busy)?;
 
-        for _i in 0..6 {
-            // bits will be inverted upon sending
-            self.enqueue_sextet(0x2a);
+        if !self.busy {
+            self.start_frame()?;
         }
-        self.enqueue_sextet(0x38);
-        self.enqueue_sextet(0x2c);
 
-        let msg_len = content.len() + 7;
-        self.enqueue_bytes(&[msg_len as u8, from, to, id, header_flags])?;
-        self.enqueue_bytes(content)?;
+        Ok(())
+    }
+
+    /// Whether there is a packet going out (or waiting to).
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    /// Returns `true` once after the queue has been fully sent.
+    pub fn take_tx_done(&mut self) -> bool {
+        mem::replace(&mut self.tx_done, false)
+    }
+
+    /// Key the transmitter with `signal` for `duration_ms`, capped at `MAX_TEST_DURATION_MS`.
+    /// It isn't charged to any airtime budget, that's up to the caller.
+    ///
+    /// The output is switched off by `tick` once the time is up, so it needs the timer interrupt
+    /// like any other transmission. Frames sent in the meantime are queued until it's over.
+    pub fn start_test(&mut self, signal: TestSignal, duration_ms: u32) -> Result<(), Error> {
+        if self.busy {
+            return Err(Error::Busy);
+        }
+
+        let tick_rate = match signal {
+            TestSignal::Carrier => CARRIER_TICK_RATE,
+            // two ticks per period
+            TestSignal::SquareWave(frequency) => {
+                frequency.0.checked_mul(2).ok_or(Error::InvalidBitRate)?
+            }
+        };
+        if !(MIN_BIT_RATE..=MAX_BIT_RATE).contains(&tick_rate) {
+            return Err(Error::InvalidBitRate);
+        }
 
-        let crc = !self.fcs;
-        self.enqueue_bytes(&[(crc & 0xff) as u8, (crc >> 8) as u8])?;
+        let duration_ms = cmp::min(duration_ms, MAX_TEST_DURATION_MS);
+        self.test = Some(TestState {
+            ticks_left: (duration_ms as u64 * tick_rate as u64 / 1000) as u32,
+            square: signal != TestSignal::Carrier,
+            level: true,
+        });
+        self.busy = true;
 
-        self.flush()?;
+        self.set_ptt(true)?;
+        self.set_bit(true)?;
+        self.timer.start(Hertz(tick_rate));
+        Ok(())
+    }
 
+    /// End a test transmission early.
+    pub fn stop_test(&mut self) -> Result<(), Error> {
+        if self.test.take().is_some() {
+            self.set_bit(false)?;
+            self.next_frame()?;
+        }
         Ok(())
     }
 
+    /// Whether a test transmission is going on.
+    pub fn is_testing(&self) -> bool {
+        self.test.is_some()
+    }
+
+    /// Clock out the next bit. Meant to be called from the timer interrupt.
+    ///
+    /// Errors abort the transmission and are also reported by the next `send_packet`.
+    pub fn tick(&mut self) -> Result<(), Error> {
+        self.clock_out().inspect_err(|err| {
+            self.abort();
+            self.fault = Some(*err);
+        })
+    }
+
+    fn clock_out(&mut self) -> Result<(), Error> {
+        // this also clears the timer's update flag
+        if self.timer.wait().is_err() || !self.busy {
+            return Ok(());
+        }
+
+        if let Some(test) = self.test.as_mut() {
+            if test.ticks_left == 0 {
+                return self.stop_test();
+            }
+            test.ticks_left -= 1;
+            if test.square {
+                test.level = !test.level;
+            }
+            let level = test.level;
+            return self.set_bit(level);
+        }
+
+        self.bit_ptr += 1;
+
+        let next_bit = self
+            .queue
+            .peek()
+            .and_then(|frame| frame.level(self.bit_ptr));
+
+        match next_bit {
+            Some(bit) => self.set_bit(bit),
+            None => {
+                // we're done with this frame
+                self.queue.dequeue();
+                self.set_bit(false)?;
+                self.next_frame()
+            }
+        }
+    }
+
+    // start on whatever is queued up, or stop
+    fn next_frame(&mut self) -> Result<(), Error> {
+        if self.queue.peek().is_some() {
+            self.start_frame()
+        } else {
+            // if the timer had already been stopped, something else is messing with it
+            self.timer.cancel().map_err(|_| Error::TimerFault)?;
+            self.busy = false;
+            self.tx_done = true;
+            self.set_ptt(false)
+        }
+    }
+
+    fn abort(&mut self) {
+        self.timer.cancel().ok();
+        self.test = None;
+        while self.queue.dequeue().is_some() {}
+        self.busy = false;
+        self.set_bit(false).ok();
+        self.set_ptt(false).ok();
+    }
+
+    fn start_frame(&mut self) -> Result<(), Error> {
+        if !self.busy {
+            self.set_ptt(true)?;
+        }
+
+        self.bit_ptr = 0;
+        self.busy = true;
+
+        let (first_bit, bit_rate) = match self.queue.peek() {
+            Some(frame) => (frame.level(0).unwrap_or(false), frame.bit_rate()),
+            None => (false, self.config.bit_rate),
+        };
+        self.set_bit(first_bit)?;
+        self.timer.start(bit_rate);
+        Ok(())
+    }
+
+    fn set_ptt(&mut self, on: bool) -> Result<(), Error> {
+        match self.config.ptt.as_mut() {
+            Some(ptt) if on => ptt.set_high().map_err(|_| Error::Port),
+            Some(ptt) => ptt.set_low().map_err(|_| Error::Port),
+            None => Ok(()),
+        }
+    }
+
     fn set_bit(&mut self, bit: bool) -> Result<(), Error> {
-        if bit {
+        if bit != self.config.inverted {
             self.pin.set_high().map_err(|_| Error::Port)?;
         } else {
             self.pin.set_low().map_err(|_| Error::Port)?;
         }
         Ok(())
     }
+}
+
+impl<P, T, E> RfEncoder for RadioHeadASK<P, T, E>
+where
+    P: OutputPin,
+    T: CountDown<Time = Hertz> + Periodic + Cancel,
+    E: OutputPin,
+{
+    type Error = Error;
+
+    fn encode(&self, header: &Header, payload: &[u8]) -> Result<OokFrame, Error> {
+        encode(&self.config, header, payload)
+    }
 
-    fn enqueue_sextet(&mut self, sextet: u8) {
-        self.buffer[self.buffer_ptr] = sextet;
-        self.buffer_ptr += 1;
+    fn send_raw(&mut self, frame: OokFrame) -> Result<(), Error> {
+        self.send_ook(frame)
     }
 
-    fn flush(&mut self) -> Result<(), Error> {
-        self.timer.start(2000.hz());
+    fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    fn airtime_us(&self, payload_len: usize) -> u32 {
+        rf::airtime_us(frame_bits(&self.config, payload_len), self.config.bit_rate)
+    }
+}
+
+/// RadioHead ASK receiver.
+///
+/// `sample` should be called at 8x the bit rate (e.g. from a timer interrupt).
+pub struct RadioHeadASKReceiver<P>
+where
+    P: InputPin,
+{
+    pin: P,
+    pll_ramp: u8,
+    integrator: u8,
+    last_sample: bool,
+    bits: u16,
+    active: bool,
+    bit_count: u8,
+    buffer: [u8; MAX_CODED_LEN],
+    buffer_len: usize,
+    fec: bool,
+    inverted: bool,
+    pending_corrections: u32,
+    corrected_bits: u32,
+}
+
+impl<P> RadioHeadASKReceiver<P>
+where
+    P: InputPin,
+{
+    pub fn new(pin: P) -> Self {
+        Self {
+            pin,
+            pll_ramp: 0,
+            integrator: 0,
+            last_sample: false,
+            bits: 0,
+            active: false,
+            bit_count: 0,
+            buffer: [0_u8; MAX_CODED_LEN],
+            buffer_len: 0,
+            fec: false,
+            inverted: false,
+            pending_corrections: 0,
+            corrected_bits: 0,
+        }
+    }
+
+    /// Expect frames with error correction (see `Config::fec`).
+    pub fn fec(mut self, fec: bool) -> Self {
+        self.fec = fec;
+        self
+    }
+
+    /// The input is active-low (see `Config::inverted`).
+    pub fn inverted(mut self, inverted: bool) -> Self {
+        self.inverted = inverted;
+        self
+    }
+
+    /// How many bits the FEC has fixed so far, in packets which made it.
+    pub fn corrected_bits(&self) -> u32 {
+        self.corrected_bits
+    }
+
+    /// Read the input pin and feed it to the demodulator.
+    pub fn sample(&mut self) -> nb::Result<Packet, Error> {
+        let level = self.pin.is_high().map_err(|_| Error::Port)?;
+        self.feed(level)
+    }
+
+    /// Feed a single sample (as read from the pin) to the demodulator. Returns a packet once a
+    /// full (and valid) one has been received.
+    pub fn feed(&mut self, level: bool) -> nb::Result<Packet, Error> {
+        let level = level != self.inverted;
+        if level {
+            self.integrator += 1;
+        }
+
+        if level != self.last_sample {
+            self.pll_ramp += if self.pll_ramp < RAMP_TRANSITION {
+                RAMP_INC_RETARD
+            } else {
+                RAMP_INC_ADVANCE
+            };
+            self.last_sample = level;
+        } else {
+            self.pll_ramp += RAMP_INC;
+        }
 
-        for n in 0..self.buffer_ptr {
-            for i in 0..6 {
-                self.set_bit((self.buffer[n] & (0x01 << i)) > 0)?;
-                block!(self.timer.wait()).unwrap();
+        if self.pll_ramp < RX_RAMP_LEN {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // we've got a full bit: most of the samples decide its value
+        self.bits >>= 1;
+        if self.integrator >= 5 {
+            self.bits |= 0x800;
+        }
+        self.pll_ramp -= RX_RAMP_LEN;
+        self.integrator = 0;
+
+        if !self.active {
+            if self.bits == START_SYMBOL {
+                self.active = true;
+                self.bit_count = 0;
+                self.buffer_len = 0;
+                self.pending_corrections = 0;
             }
+            return Err(nb::Error::WouldBlock);
         }
-        self.set_bit(false).unwrap();
-        Ok(())
+
+        self.bit_count += 1;
+        if self.bit_count < 12 {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.bit_count = 0;
+
+        let (high, low) = ((self.bits & 0x3f) as u8, (self.bits >> 6) as u8);
+
+        if self.fec {
+            // whatever went wrong with a symbol, the FEC gets a chance to fix it
+            let byte = (nearest_symbol_6to4(high) << 4) | nearest_symbol_6to4(low);
+            return self.receive_coded(byte);
+        }
+
+        let byte = match (symbol_6to4(high), symbol_6to4(low)) {
+            (Some(high), Some(low)) => (high << 4) | low,
+            _ => {
+                self.active = false;
+                return Err(nb::Error::Other(Error::InvalidSymbol));
+            }
+        };
+
+        // the first byte is the message length
+        if self.buffer_len == 0 && (byte < 7 || byte as usize > MAX_PAYLOAD_LEN) {
+            self.active = false;
+            return Err(nb::Error::Other(Error::InvalidLength));
+        }
+
+        self.buffer[self.buffer_len] = byte;
+        self.buffer_len += 1;
+
+        if self.buffer_len < self.buffer[0] as usize {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.active = false;
+        parse(&self.buffer[..self.buffer_len]).map_err(nb::Error::Other)
     }
 
-    fn enqueue_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
-        bytes.iter().for_each(|b| {
-            self.enqueue_sextet(SYMBOLS[(b >> 4) as usize]);
-            self.enqueue_sextet(SYMBOLS[(b & 0xf) as usize]);
-            self.update_fcs(*b);
-        });
-        Ok(())
+    fn receive_coded(&mut self, byte: u8) -> nb::Result<Packet, Error> {
+        self.buffer[self.buffer_len] = byte;
+        self.buffer_len += 1;
+
+        if !self.buffer_len.is_multiple_of(fec::CODED_BLOCK_LEN) {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // decoded blocks take half the room, so they go at the start of the buffer
+        // without ever catching up with the coded ones
+        let block = self.buffer_len / fec::CODED_BLOCK_LEN - 1;
+        let coded: [u8; fec::CODED_BLOCK_LEN] = self.buffer
+            [block * fec::CODED_BLOCK_LEN..self.buffer_len]
+            .try_into()
+            .unwrap();
+        let (data, corrected) = match fec::decode_block(&coded) {
+            Some(decoded) => decoded,
+            None => {
+                self.active = false;
+                return Err(nb::Error::Other(Error::Uncorrectable));
+            }
+        };
+        self.buffer[block * fec::BLOCK_LEN..(block + 1) * fec::BLOCK_LEN].copy_from_slice(&data);
+
+        // the first byte is the message length
+        let msg_len = self.buffer[0] as usize;
+        if !(7..=MAX_PAYLOAD_LEN).contains(&msg_len) {
+            self.active = false;
+            return Err(nb::Error::Other(Error::InvalidLength));
+        }
+
+        self.pending_corrections += corrected as u32;
+        if self.buffer_len < fec::coded_len(msg_len) {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.active = false;
+        let packet = parse(&self.buffer[..msg_len]).map_err(nb::Error::Other)?;
+        self.corrected_bits += mem::replace(&mut self.pending_corrections, 0);
+        Ok(packet)
+    }
+}
+
+fn parse(buf: &[u8]) -> Result<Packet, Error> {
+    if buf.iter().fold(0xffff, |fcs, b| update_fcs(fcs, *b)) != FCS_RESIDUE {
+        return Err(Error::WrongChecksum);
+    }
+
+    Ok(Packet {
+        to: buf[1],
+        from: buf[2],
+        id: buf[3],
+        flags: buf[4],
+        payload: Vec::from_slice(&buf[5..buf.len() - 2]).map_err(|_| Error::InvalidLength)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+    use core::convert::Infallible;
+    use std::{cell::Cell, iter, rc::Rc, vec::Vec as StdVec};
+
+    // the pin's level, shared with the test
+    struct Line(Rc<Cell<bool>>);
+
+    impl OutputPin for Line {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.set(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.0.set(true);
+            Ok(())
+        }
+    }
+
+    impl InputPin for Line {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Self::Error> {
+            Ok(self.0.get())
+        }
+
+        fn is_low(&self) -> Result<bool, Self::Error> {
+            Ok(!self.0.get())
+        }
+    }
+
+    // a timer which has always just fired
+    struct Expired;
+
+    impl CountDown for Expired {
+        type Time = Hertz;
+
+        fn start<T: Into<Hertz>>(&mut self, _: T) {}
+
+        fn wait(&mut self) -> nb::Result<(), void::Void> {
+            Ok(())
+        }
+    }
+
+    impl Periodic for Expired {}
+
+    impl Cancel for Expired {
+        type Error = Infallible;
+
+        fn cancel(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    const HEADER: Header = Header {
+        from: 0x12,
+        to: 0xff,
+        id: 0x34,
+        flags: 0x05,
+    };
+
+    // what the pin does while `content` is sent, one level per bit
+    fn transmit(config: Config, content: &[u8]) -> StdVec<bool> {
+        let line = Rc::new(Cell::new(false));
+        let mut radio = RadioHeadASK::new(Line(line.clone()), Expired, config).unwrap();
+        let mut levels = StdVec::new();
+
+        radio.send_frame(&HEADER, content).unwrap();
+        while radio.is_busy() {
+            levels.push(line.get());
+            radio.tick().unwrap();
+        }
+        levels
+    }
+
+    fn receive(
+        receiver: &mut RadioHeadASKReceiver<Line>,
+        idle: bool,
+        levels: &[bool],
+    ) -> StdVec<Packet> {
+        let samples = iter::repeat_n(idle, 100)
+            .chain(levels.iter().flat_map(|l| iter::repeat_n(*l, 8)))
+            .chain(iter::repeat_n(idle, 100));
+
+        samples
+            .filter_map(|sample| receiver.feed(sample).ok())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for &fec in &[false, true] {
+            for &inverted in &[false, true] {
+                for &len in &[0, 1, 4, 5, 13, MAX_MESSAGE_LEN] {
+                    let content: StdVec<u8> = (0..len as u8).map(|n| n.wrapping_mul(37)).collect();
+                    let config = Config::default().fec(fec).inverted(inverted);
+                    let levels = transmit(config, &content);
+
+                    let frame = encode(&Config::default().fec(fec), &HEADER, &content).unwrap();
+                    assert_eq!(levels.len(), frame.num_bits());
+                    assert!(levels
+                        .iter()
+                        .zip(frame.levels())
+                        .all(|(l, f)| *l == (f != inverted)));
+
+                    let mut receiver = RadioHeadASKReceiver::new(Line(Rc::new(Cell::new(false))))
+                        .fec(fec)
+                        .inverted(inverted);
+                    let packets = receive(&mut receiver, inverted, &levels);
+
+                    assert_eq!(
+                        packets.len(),
+                        1,
+                        "fec {} inverted {} len {}",
+                        fec,
+                        inverted,
+                        len
+                    );
+                    let packet = &packets[0];
+                    assert_eq!(
+                        (packet.from, packet.to, packet.id, packet.flags),
+                        (HEADER.from, HEADER.to, HEADER.id, HEADER.flags)
+                    );
+                    assert_eq!(&packet.payload[..], &content[..]);
+                }
+            }
+        }
+    }
+
+    // the share of frames which make it through with `flips` random levels flipped, and how many
+    // bits the FEC corrected on the way
+    fn recovered(fec: bool, flips: usize, rng: &mut Rng) -> (f32, u32) {
+        const FRAMES: usize = 500;
+        let content: StdVec<u8> = (0..20).collect();
+        let levels = transmit(Config::default().fec(fec), &content);
+        // the preamble and the start symbol are left alone, there's nothing to correct if
+        // the frame isn't even noticed
+        let start = (Config::default().preamble_len as u32 + 2) * 6;
+
+        let mut receiver = RadioHeadASKReceiver::new(Line(Rc::new(Cell::new(false)))).fec(fec);
+        let mut received = 0;
+        for _ in 0..FRAMES {
+            let mut corrupted = levels.clone();
+            for _ in 0..flips {
+                let n = rng.between(start, levels.len() as u32 - 1) as usize;
+                corrupted[n] = !corrupted[n];
+            }
+            received += receive(&mut receiver, false, &corrupted)
+                .iter()
+                .filter(|packet| packet.payload[..] == content[..])
+                .count();
+        }
+        (received as f32 / FRAMES as f32, receiver.corrected_bits())
+    }
+
+    #[test]
+    fn fec_recovers_flipped_bits() {
+        let mut rng = Rng::new(0x0bad_f00d);
+
+        // without it, a single flipped bit is the end of the frame
+        assert_eq!(recovered(false, 1, &mut rng).0, 0.0);
+
+        let (one, corrected) = recovered(true, 1, &mut rng);
+        assert_eq!(one, 1.0);
+        assert!(corrected > 0);
+
+        // two bits may end up in the same codeword, which is only detected
+        let (two, _) = recovered(true, 2, &mut rng);
+        println!(
+            "FEC recovers {}% of frames with a flipped bit, {}% with two",
+            one * 100.0,
+            two * 100.0
+        );
+        assert!(two > 0.9);
+    }
+
+    #[test]
+    fn test_signals_stop() {
+        let line = Rc::new(Cell::new(false));
+        let mut radio = RadioHeadASK::new(Line(line.clone()), Expired, Config::default()).unwrap();
+
+        assert_eq!(
+            radio.start_test(TestSignal::SquareWave(Hertz(u32::MAX)), 1000),
+            Err(Error::InvalidBitRate)
+        );
+        assert!(!radio.is_testing() && !line.get());
+
+        // 1000 Hz is 2000 ticks per second
+        radio
+            .start_test(TestSignal::SquareWave(Hertz(1000)), 10)
+            .unwrap();
+        let mut ticks = 0;
+        while radio.is_testing() {
+            radio.tick().unwrap();
+            ticks += 1;
+        }
+        assert_eq!(ticks, 21);
+        assert!(!radio.is_busy() && !line.get());
+    }
+
+    #[test]
+    fn parse_rejects_corrupted_messages() {
+        let content = [1, 2, 3];
+        let mut message = StdVec::from(&[10, HEADER.to, HEADER.from, HEADER.id, HEADER.flags][..]);
+        message.extend_from_slice(&content);
+        let crc = !message.iter().fold(0xffff, |fcs, b| update_fcs(fcs, *b));
+        message.extend_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(&parse(&message).unwrap().payload[..], &content[..]);
+        for n in 0..message.len() {
+            let mut corrupted = message.clone();
+            corrupted[n] ^= 0x10;
+            assert_eq!(parse(&corrupted), Err(Error::WrongChecksum));
+        }
+    }
+
+    #[test]
+    fn frame_bits_matches_encode() {
+        for &fec in &[false, true] {
+            let config = Config::default().fec(fec);
+            for len in 0..=MAX_MESSAGE_LEN {
+                let frame = encode(&config, &HEADER, &[0; MAX_MESSAGE_LEN][..len]).unwrap();
+                assert_eq!(frame.num_bits(), frame_bits(&config, len));
+            }
+        }
+        assert_eq!(
+            encode(&Config::default(), &HEADER, &[0; MAX_MESSAGE_LEN + 1]).err(),
+            Some(Error::PayloadTooLong)
+        );
     }
 }
@@ -0,0 +1,25 @@
+// Range-test frames, for placing nodes and antennas. They're RadioHead packets, sent at a fixed
+// rate, whose payload is:
+//
+//  0-1   "RT"
+//  2-5   sequence number (little-endian), starting at 0 on every boot
+//  6-8   0x55 filler, so that they're as long as sensor payloads
+//
+// `contrib/range_test/range_test.py` works out the packet error rate from what `rtl_433` receives.
+
+use heapless::{consts::*, Vec};
+
+use crate::{payload::PAYLOAD_LEN, wire::Encode};
+
+pub const MAGIC: [u8; 2] = *b"RT";
+
+const FILLER: u8 = 0x55;
+
+pub fn payload(sequence: u32) -> Vec<u8, U16> {
+    let mut buf = Vec::new();
+    // can't fail, the buffer is big enough
+    buf.resize(PAYLOAD_LEN, FILLER).unwrap();
+    buf[0..2].copy_from_slice(&MAGIC);
+    sequence.encode(&mut buf[2..6]);
+    buf
+}
@@ -0,0 +1,385 @@
+// Transmitters, as seen by the code which decides what to send and when. The line code
+// (RadioHead ASK, Manchester, PWM, ...) is up to the implementation.
+
+use core::convert::TryFrom;
+use heapless::{consts::*, Vec};
+
+use crate::{radiohead_ask, rng::Rng};
+
+/// Longest frame, in bits: enough for a RadioHead packet of the maximum size, with FEC.
+pub const MAX_FRAME_BITS: usize = 1840;
+
+/// Bit rate, or any other frequency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Hertz(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    Port,
+    Busy,
+    PayloadTooLong,
+    TimerFault,
+    InvalidBitRate,
+    InvalidPreambleLength,
+    InvalidSymbol,
+    InvalidLength,
+    WrongChecksum,
+    Uncorrectable,
+    DutyCycleExceeded,
+    TooManyRepeats,
+    GapTooLong,
+}
+
+/// Addressing part of a frame, RadioHead style.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub from: u8,
+    pub to: u8,
+    pub id: u8,
+    pub flags: u8,
+}
+
+/// Raw output levels, clocked out one per tick at `bit_rate`.
+///
+/// RadioHead packets end up as one of these, but it can be used for other line codes too.
+pub struct OokFrame {
+    bit_rate: Hertz,
+    buffer: [u8; MAX_FRAME_BITS / 8],
+    num_bits: usize,
+    // ticks at low level before the frame starts
+    lead_in: u16,
+    // the frame goes out again after each of these (low) gaps, in ticks (up to 8 of them)
+    repeat_gaps: Vec<u16, U8>,
+}
+
+impl OokFrame {
+    pub fn new(bit_rate: Hertz) -> Self {
+        Self {
+            bit_rate,
+            buffer: [0_u8; MAX_FRAME_BITS / 8],
+            num_bits: 0,
+            lead_in: 0,
+            repeat_gaps: Vec::new(),
+        }
+    }
+
+    /// Append `ticks` bits at `level`.
+    pub fn push(&mut self, level: bool, ticks: usize) -> Result<(), Error> {
+        if self.num_bits + ticks > MAX_FRAME_BITS {
+            return Err(Error::PayloadTooLong);
+        }
+
+        for _ in 0..ticks {
+            if level {
+                self.buffer[self.num_bits / 8] |= 0x01 << (self.num_bits % 8);
+            }
+            self.num_bits += 1;
+        }
+        Ok(())
+    }
+
+    pub fn num_bits(&self) -> usize {
+        self.num_bits
+    }
+
+    /// Stay low for `ticks` ticks before the frame (and its repeats) go out, to space it out
+    /// from whatever was sent before.
+    pub fn lead_in(&mut self, ticks: u16) {
+        self.lead_in = ticks;
+    }
+
+    /// Send the whole frame once more, after `gap_ticks` ticks at low level.
+    pub fn repeat(&mut self, gap_ticks: u16) -> Result<(), Error> {
+        self.repeat_gaps
+            .push(gap_ticks)
+            .map_err(|_| Error::TooManyRepeats)
+    }
+
+    pub fn num_repeats(&self) -> usize {
+        self.repeat_gaps.len()
+    }
+
+    pub fn bit_rate(&self) -> Hertz {
+        self.bit_rate
+    }
+
+    /// Number of ticks in `ms`, for gaps.
+    pub fn ticks(&self, ms: u32) -> Result<u16, Error> {
+        u16::try_from(ms as u64 * self.bit_rate.0 as u64 / 1000).map_err(|_| Error::GapTooLong)
+    }
+
+    /// Time spent sending the frame (and its repeats, but not the gaps), in us.
+    pub fn airtime_us(&self) -> u32 {
+        airtime_us(self.num_bits * (self.repeat_gaps.len() + 1), self.bit_rate)
+    }
+
+    /// All the levels, repeats included, in the order they go out.
+    pub fn levels(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..)
+            .map(move |n| self.level(n))
+            .take_while(Option::is_some)
+            .flatten()
+    }
+
+    /// Level at tick `n`, counting the lead-in, the repeats and the gaps. `None` once it's over.
+    pub fn level(&self, n: usize) -> Option<bool> {
+        let mut n = match n.checked_sub(self.lead_in as usize) {
+            Some(n) => n,
+            None => return Some(false),
+        };
+        for gap in self.repeat_gaps.iter().map(|g| *g as usize) {
+            if n < self.num_bits {
+                return self.bit(n);
+            } else if n < self.num_bits + gap {
+                return Some(false);
+            }
+            n -= self.num_bits + gap;
+        }
+        self.bit(n)
+    }
+
+    fn bit(&self, n: usize) -> Option<bool> {
+        if n < self.num_bits {
+            Some((self.buffer[n / 8] & (0x01 << (n % 8))) > 0)
+        } else {
+            None
+        }
+    }
+}
+
+/// A transmitter. Wrappers (`Repeated`, `airtime::Metered`) work with any of them whose errors
+/// can carry an `Error`.
+pub trait RfEncoder {
+    type Error;
+
+    /// Encode a frame into the levels that go on the air.
+    fn encode(&self, header: &Header, payload: &[u8]) -> Result<OokFrame, Self::Error>;
+
+    /// Queue levels for sending, from `encode` or from another protocol.
+    fn send_raw(&mut self, frame: OokFrame) -> Result<(), Self::Error>;
+
+    /// Encode a frame and queue it for sending.
+    fn send_frame(&mut self, header: &Header, payload: &[u8]) -> Result<(), Self::Error> {
+        let frame = self.encode(header, payload)?;
+        self.send_raw(frame)
+    }
+
+    /// Whether there is a frame going out (or waiting to).
+    fn is_busy(&self) -> bool;
+
+    /// Time it takes to send a frame with `payload_len` bytes of payload, in us.
+    fn airtime_us(&self, payload_len: usize) -> u32;
+}
+
+pub fn airtime_us(num_bits: usize, bit_rate: Hertz) -> u32 {
+    (num_bits as u64 * 1_000_000 / bit_rate.0 as u64) as u32
+}
+
+/// Sends every frame `repeats` more times, after a random gap (in ms) within `gap_ms`,
+/// like commercial sensors do.
+pub struct Repeated<'t, R> {
+    radio: &'t mut R,
+    rng: &'t mut Rng,
+    repeats: u8,
+    gap_ms: (u16, u16),
+}
+
+impl<'t, R> Repeated<'t, R>
+where
+    R: RfEncoder,
+    R::Error: From<Error>,
+{
+    pub fn new(radio: &'t mut R, rng: &'t mut Rng, repeats: u8, gap_ms: (u16, u16)) -> Self {
+        Self {
+            radio,
+            rng,
+            repeats,
+            gap_ms,
+        }
+    }
+}
+
+impl<'t, R> RfEncoder for Repeated<'t, R>
+where
+    R: RfEncoder,
+    R::Error: From<Error>,
+{
+    type Error = R::Error;
+
+    fn encode(&self, header: &Header, payload: &[u8]) -> Result<OokFrame, Self::Error> {
+        self.radio.encode(header, payload)
+    }
+
+    fn send_raw(&mut self, mut frame: OokFrame) -> Result<(), Self::Error> {
+        for _ in 0..self.repeats {
+            let gap_ms = self.rng.between(self.gap_ms.0 as u32, self.gap_ms.1 as u32);
+            frame.repeat(frame.ticks(gap_ms)?)?;
+        }
+        self.radio.send_raw(frame)
+    }
+
+    fn is_busy(&self) -> bool {
+        self.radio.is_busy()
+    }
+
+    fn airtime_us(&self, payload_len: usize) -> u32 {
+        self.radio.airtime_us(payload_len) * (self.repeats as u32 + 1)
+    }
+}
+
+/// Encodes frames like `RadioHeadASK` but, instead of sending them, records the
+/// levels as `(level, duration in us)`, with consecutive bits at the same level merged.
+///
+/// Meant for running the scheduling code on a host.
+pub struct MockEncoder {
+    pub config: radiohead_ask::Config,
+    pub timings: Vec<(bool, u32), U1024>,
+    pub num_frames: usize,
+    /// what `is_busy` returns
+    pub busy: bool,
+}
+
+impl MockEncoder {
+    pub fn new(config: radiohead_ask::Config) -> Self {
+        Self {
+            config,
+            timings: Vec::new(),
+            num_frames: 0,
+            busy: false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        // not `Vec::clear`: heapless 0.5's `truncate` indexes past the end of the buffer
+        self.timings = Vec::new();
+        self.num_frames = 0;
+    }
+
+    fn record(&mut self, frame: &OokFrame) -> Result<(), Error> {
+        let bit_us = airtime_us(1, frame.bit_rate());
+
+        for level in frame.levels() {
+            match self.timings.last_mut() {
+                Some((last, duration)) if *last == level => *duration += bit_us,
+                _ => self
+                    .timings
+                    .push((level, bit_us))
+                    .map_err(|_| Error::Busy)?,
+            }
+        }
+        self.num_frames += 1;
+        Ok(())
+    }
+}
+
+impl RfEncoder for MockEncoder {
+    type Error = Error;
+
+    fn encode(&self, header: &Header, payload: &[u8]) -> Result<OokFrame, Self::Error> {
+        radiohead_ask::encode(&self.config, header, payload)
+    }
+
+    fn send_raw(&mut self, frame: OokFrame) -> Result<(), Self::Error> {
+        self.record(&frame)
+    }
+
+    fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    fn airtime_us(&self, payload_len: usize) -> u32 {
+        airtime_us(
+            radiohead_ask::frame_bits(&self.config, payload_len),
+            self.config.bit_rate,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: Header = Header {
+        from: 1,
+        to: 2,
+        id: 3,
+        flags: 0,
+    };
+
+    fn total_us(radio: &MockEncoder) -> u32 {
+        radio.timings.iter().map(|(_, us)| us).sum()
+    }
+
+    #[test]
+    fn mock_records_levels() {
+        let mut radio = MockEncoder::new(radiohead_ask::Config::default());
+        radio.send_frame(&HEADER, &[0xaa; 10]).unwrap();
+
+        assert_eq!(radio.num_frames, 1);
+        assert_eq!(total_us(&radio), radio.airtime_us(10));
+        // the preamble (0x2a sextets, LSB first) alternates at 2000 bit/s
+        assert_eq!(
+            &radio.timings[..4],
+            &[(false, 500), (true, 500), (false, 500), (true, 500)]
+        );
+        // consecutive levels are merged
+        assert!(radio.timings.windows(2).all(|w| w[0].0 != w[1].0));
+
+        radio.clear();
+        assert!(radio.timings.is_empty());
+        assert_eq!(radio.num_frames, 0);
+    }
+
+    #[test]
+    fn lead_in_is_not_airtime() {
+        let mut radio = MockEncoder::new(radiohead_ask::Config::default());
+        let mut frame = radio.encode(&HEADER, &[1]).unwrap();
+        let airtime_us = frame.airtime_us();
+        frame.lead_in(frame.ticks(100).unwrap());
+        radio.send_raw(frame).unwrap();
+
+        assert_eq!(radio.timings[0], (false, 100_000 + 500));
+        assert_eq!(total_us(&radio), 100_000 + airtime_us);
+        assert_eq!(radio.airtime_us(1), airtime_us);
+    }
+
+    #[test]
+    fn repeated_frames_have_gaps() {
+        let mut mock = MockEncoder::new(radiohead_ask::Config::default());
+        let mut rng = Rng::new(1);
+        let frame_us = mock.airtime_us(4);
+
+        for _ in 0..20 {
+            mock.clear();
+            let mut radio = Repeated::new(&mut mock, &mut rng, 2, (20, 100));
+            assert_eq!(radio.airtime_us(4), frame_us * 3);
+            radio.send_frame(&HEADER, &[1, 2, 3, 4]).unwrap();
+
+            // one frame, sent three times with two gaps in between
+            assert_eq!(mock.num_frames, 1);
+            let gaps_us = total_us(&mock) - frame_us * 3;
+            assert!((40_000..=200_000).contains(&gaps_us), "{} us", gaps_us);
+        }
+    }
+
+    #[test]
+    fn repeated_frames_are_limited() {
+        let mut mock = MockEncoder::new(radiohead_ask::Config::default());
+        let mut rng = Rng::new(1);
+        let mut radio = Repeated::new(&mut mock, &mut rng, 9, (20, 100));
+
+        assert_eq!(radio.send_frame(&HEADER, &[]), Err(Error::TooManyRepeats));
+        assert_eq!(mock.num_frames, 0);
+    }
+
+    #[test]
+    fn repeat_gaps_must_fit() {
+        let mut mock = MockEncoder::new(radiohead_ask::Config::default());
+        let mut rng = Rng::new(1);
+        // 40 s at 2000 bit/s is more ticks than a gap can have
+        let mut radio = Repeated::new(&mut mock, &mut rng, 1, (40_000, 40_000));
+
+        assert_eq!(radio.send_frame(&HEADER, &[]), Err(Error::GapTooLong));
+        assert_eq!(mock.num_frames, 0);
+    }
+}
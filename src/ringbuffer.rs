@@ -21,23 +21,10 @@ where
         }
     }
 
-    pub fn clear(&mut self) {
-        self.tail = 0;
-        self.head = 0;
-    }
-
     pub fn is_empty(&self) -> bool {
         self.head == self.tail
     }
 
-    pub fn size(&self) -> u16 {
-        if self.tail >= self.head {
-            self.tail - self.head
-        } else {
-            (BUFFER_SIZE - self.head) + self.tail
-        }
-    }
-
     pub fn push(&mut self, c: T) {
         let buf_ref = self
             .contents
@@ -77,7 +64,7 @@ where
     }
 }
 
-impl<'t, T> Iterator for RingBuffer<T>
+impl<T> Iterator for RingBuffer<T>
 where
     T: Default + Copy,
 {
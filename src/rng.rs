@@ -0,0 +1,27 @@
+// xorshift32. Nowhere near good enough for anything that has to be unpredictable,
+// it's only used to spread transmissions out.
+
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        // it would be stuck at 0 forever
+        Self {
+            state: if seed == 0 { 0x2545_f491 } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    /// A number in `low..=high`.
+    pub fn between(&mut self, low: u32, high: u32) -> u32 {
+        low + self.next_u32() % (high - low + 1)
+    }
+}
@@ -0,0 +1,180 @@
+// Senseair S8 CO2 sensor, which speaks Modbus RTU over the same UART wiring as the MH-Z19B.
+
+use embedded_hal::serial::{Read, Write};
+use nb::block;
+
+use crate::{
+    clock::Clock,
+    modbus::{self, Master},
+};
+
+/// Every S8 answers to this, whatever its own address is.
+pub const ANY_ADDRESS: u8 = 0xfe;
+
+/// Top of the output range; the sensor is only specified up to 2000 ppm, though.
+pub const MAX_PPM: u16 = 10_000;
+
+// input registers (IR1 is at 0), from "meter status" to "space CO2"
+const IR_METER_STATUS: u16 = 0;
+const IR_SPACE_CO2: u16 = 3;
+
+// holding register with the ABC period, in hours (0 turns ABC off)
+const HR_ABC_PERIOD: u16 = 31;
+// the sensor's default
+const ABC_PERIOD_H: u16 = 180;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct S8Reading {
+    pub co2_ppm: u16,
+    /// meter status: 0 when all is well, bit 5 when out of range, anything else is an error
+    pub status: u16,
+}
+
+/// S8 driver. Like `Mhz19b`, readings are asked for with `start_request`, then `poll`ed for.
+pub struct S8<S, C> {
+    modbus: Master<S, C>,
+    address: u8,
+}
+
+impl<S, C> S8<S, C>
+where
+    S: Read<u8> + Write<u8>,
+    C: Clock,
+{
+    pub fn new(serial: S, clock: C) -> Self {
+        Self {
+            modbus: Master::new(serial, clock),
+            address: ANY_ADDRESS,
+        }
+    }
+
+    /// For when there's more than one sensor on the bus.
+    pub fn address(mut self, address: u8) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub fn release(self) -> (S, C) {
+        self.modbus.release()
+    }
+
+    /// Automatic baseline correction takes the lowest reading of every 180 h as 400 ppm. It's
+    /// on by default, and only right for places which get some fresh air every week.
+    pub fn set_abc(&mut self, enabled: bool) -> Result<(), modbus::Error> {
+        let period = if enabled { ABC_PERIOD_H } else { 0 };
+        self.modbus
+            .write_register(self.address, HR_ABC_PERIOD, period)?;
+        block!(self.modbus.poll())?;
+        Ok(())
+    }
+
+    /// Ask for a reading. Anything still pending is forgotten.
+    pub fn start_request(&mut self) -> Result<(), modbus::Error> {
+        let count = IR_SPACE_CO2 - IR_METER_STATUS + 1;
+        self.modbus
+            .read_input_registers(self.address, IR_METER_STATUS, count)
+    }
+
+    /// The reading asked for with `start_request`, once the sensor has sent it. A sensor which
+    /// stays silent or answers with an exception shows up as `modbus`'s `Timeout` or
+    /// `Exception`.
+    pub fn poll(&mut self) -> nb::Result<S8Reading, modbus::Error> {
+        let registers = self.modbus.poll()?;
+        Ok(S8Reading {
+            co2_ppm: registers[(IR_SPACE_CO2 - IR_METER_STATUS) as usize],
+            status: registers[0],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::crc16;
+    use core::convert::Infallible;
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc, vec::Vec as StdVec};
+
+    // answers every request with the next reply, and keeps the requests
+    #[derive(Clone, Default)]
+    struct Sensor {
+        requests: Rc<RefCell<StdVec<u8>>>,
+        rx: Rc<RefCell<VecDeque<u8>>>,
+        replies: Rc<RefCell<VecDeque<StdVec<u8>>>>,
+    }
+
+    impl Read<u8> for Sensor {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.rx
+                .borrow_mut()
+                .pop_front()
+                .ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    impl Write<u8> for Sensor {
+        type Error = Infallible;
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+            self.requests.borrow_mut().push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            if let Some(reply) = self.replies.borrow_mut().pop_front() {
+                self.rx.borrow_mut().extend(reply);
+            }
+            Ok(())
+        }
+    }
+
+    struct Stopped;
+
+    impl Clock for Stopped {
+        fn now_ms(&self) -> u32 {
+            0
+        }
+    }
+
+    fn with_crc(frame: &[u8]) -> StdVec<u8> {
+        let mut frame = frame.to_vec();
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn reads_co2_and_status() {
+        let sensor = Sensor::default();
+        // meter status, alarm status, output status, space CO2
+        sensor.replies.borrow_mut().push_back(with_crc(&[
+            0xfe, 0x04, 0x08, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x01, 0x90,
+        ]));
+        let mut s8 = S8::new(sensor.clone(), Stopped);
+
+        s8.start_request().unwrap();
+        // the request from the S8's documentation
+        assert_eq!(
+            *sensor.requests.borrow(),
+            [0xfe, 0x04, 0x00, 0x00, 0x00, 0x04, 0xe5, 0xc6]
+        );
+        assert_eq!(
+            s8.poll().unwrap(),
+            S8Reading {
+                co2_ppm: 400,
+                status: 0x20
+            }
+        );
+    }
+
+    #[test]
+    fn turns_abc_off() {
+        let sensor = Sensor::default();
+        let request = with_crc(&[0xfe, 0x06, 0x00, 0x1f, 0x00, 0x00]);
+        sensor.replies.borrow_mut().push_back(request.clone());
+        let mut s8 = S8::new(sensor.clone(), Stopped);
+
+        s8.set_abc(false).unwrap();
+        assert_eq!(*sensor.requests.borrow(), request);
+    }
+}
@@ -0,0 +1,428 @@
+// Sensirion SCD4x (SCD40/41) and SCD30 CO2 sensors, over I2C. They take 16-bit commands, and
+// every 16-bit word going either way is followed by its CRC-8.
+
+use embedded_hal::blocking::i2c::{Read, Write};
+
+use crate::clock::Clock;
+
+/// Top of the output range, for both of them.
+pub const MAX_PPM: u16 = 40_000;
+
+const CRC_POLYNOMIAL: u8 = 0x31;
+const CRC_INIT: u8 = 0xff;
+
+const SCD4X_ADDRESS: u8 = 0x62;
+const SCD4X_START_PERIODIC_MEASUREMENT: u16 = 0x21b1;
+const SCD4X_STOP_PERIODIC_MEASUREMENT: u16 = 0x3f86;
+const SCD4X_GET_DATA_READY_STATUS: u16 = 0xe4b8;
+const SCD4X_READ_MEASUREMENT: u16 = 0xec05;
+const SCD4X_SET_AUTOMATIC_SELF_CALIBRATION: u16 = 0x2416;
+
+const SCD30_ADDRESS: u8 = 0x61;
+const SCD30_START_CONTINUOUS_MEASUREMENT: u16 = 0x0010;
+const SCD30_STOP_CONTINUOUS_MEASUREMENT: u16 = 0x0104;
+const SCD30_GET_DATA_READY_STATUS: u16 = 0x0202;
+const SCD30_READ_MEASUREMENT: u16 = 0x0300;
+const SCD30_SET_AUTOMATIC_SELF_CALIBRATION: u16 = 0x5306;
+
+// a new measurement comes every 5 s (SCD4x) or 2 s (SCD30), and it takes a couple of commands
+// to read it (which can be the best part of a second with the system tick)
+const SCD4X_TIMEOUT_MS: u32 = 7000;
+const SCD30_TIMEOUT_MS: u32 = 4000;
+
+// how often to ask whether there's a measurement
+const READY_POLL_MS: u32 = 100;
+
+// how long they take to start up, to stop measuring, and to carry out any other command before
+// the next one (or reading its response), from the datasheets
+const SCD4X_POWER_UP_MS: u32 = 1000;
+const SCD4X_STOP_MS: u32 = 500;
+const SCD4X_COMMAND_MS: u32 = 1;
+const SCD30_POWER_UP_MS: u32 = 2000;
+const SCD30_COMMAND_MS: u32 = 3;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    WrongChecksum,
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Scd4x,
+    Scd30,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub co2_ppm: u16,
+    /// in 0.01 C
+    pub temperature: i16,
+    /// in %
+    pub humidity: u16,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Waiting,
+    // the command went out, its response can be read on the next `poll`
+    CheckingReady,
+    Reading,
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(CRC_INIT, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ CRC_POLYNOMIAL
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// SCD4x or SCD30, measuring continuously. Like `Mhz19b`, readings are asked for with
+/// `start_request`, then `poll`ed for.
+pub struct Scd<I, C> {
+    i2c: I,
+    clock: C,
+    model: Model,
+    state: State,
+    // when the request went out, and when we last asked whether there's a measurement (in ms)
+    started: u32,
+    last_check: u32,
+    // when the last command went out (or the driver was created), and how long it takes
+    last_command: u32,
+    command_ms: u32,
+}
+
+impl<I, C, E> Scd<I, C>
+where
+    I: Read<Error = E> + Write<Error = E>,
+    C: Clock,
+{
+    pub fn scd4x(i2c: I, clock: C) -> Self {
+        Self::new(i2c, clock, Model::Scd4x)
+    }
+
+    pub fn scd30(i2c: I, clock: C) -> Self {
+        Self::new(i2c, clock, Model::Scd30)
+    }
+
+    // the sensor is taken to be powered up along with the driver
+    fn new(i2c: I, clock: C, model: Model) -> Self {
+        let now = clock.now_ms();
+        Self {
+            i2c,
+            clock,
+            model,
+            state: State::Idle,
+            started: 0,
+            last_check: 0,
+            last_command: now,
+            command_ms: match model {
+                Model::Scd4x => SCD4X_POWER_UP_MS,
+                Model::Scd30 => SCD30_POWER_UP_MS,
+            },
+        }
+    }
+
+    pub fn release(self) -> (I, C) {
+        (self.i2c, self.clock)
+    }
+
+    /// Stop whatever the sensor was doing, set self-calibration up and start measuring.
+    /// It waits for the sensor to power up first, and takes up to a couple of seconds.
+    pub fn init(&mut self, self_calibration: bool) -> Result<(), Error<E>> {
+        let (stop, stop_ms, set_self_calibration) = match self.model {
+            Model::Scd4x => (
+                SCD4X_STOP_PERIODIC_MEASUREMENT,
+                SCD4X_STOP_MS,
+                SCD4X_SET_AUTOMATIC_SELF_CALIBRATION,
+            ),
+            Model::Scd30 => (
+                SCD30_STOP_CONTINUOUS_MEASUREMENT,
+                SCD30_COMMAND_MS,
+                SCD30_SET_AUTOMATIC_SELF_CALIBRATION,
+            ),
+        };
+
+        self.wait();
+        self.command(stop)?;
+        self.command_ms = stop_ms;
+        self.wait();
+        self.command_with_arg(set_self_calibration, self_calibration as u16)?;
+        self.wait();
+
+        match self.model {
+            Model::Scd4x => self.command(SCD4X_START_PERIODIC_MEASUREMENT),
+            // 0 is for no pressure compensation
+            Model::Scd30 => self.command_with_arg(SCD30_START_CONTINUOUS_MEASUREMENT, 0),
+        }
+    }
+
+    /// Ask for the next measurement. A request which is already pending carries on, so that
+    /// it still times out if the sensor never has anything.
+    pub fn start_request(&mut self) {
+        if self.state == State::Idle {
+            let now = self.clock.now_ms();
+            self.state = State::Waiting;
+            self.started = now;
+            self.last_check = now.wrapping_sub(READY_POLL_MS);
+        }
+    }
+
+    /// `WouldBlock` until there's a measurement, or `Timeout` if none comes in time.
+    pub fn poll(&mut self) -> nb::Result<Measurement, Error<E>> {
+        let result = self.step();
+        if let Err(nb::Error::Other(_)) = result {
+            self.state = State::Idle;
+        }
+        result
+    }
+
+    fn step(&mut self) -> nb::Result<Measurement, Error<E>> {
+        let now = self.clock.now_ms();
+
+        match self.state {
+            State::Idle => Err(nb::Error::WouldBlock),
+            State::Waiting => {
+                if now.wrapping_sub(self.started) > self.timeout_ms() {
+                    return Err(nb::Error::Other(Error::Timeout));
+                }
+                if now.wrapping_sub(self.last_check) >= READY_POLL_MS && !self.is_busy(now) {
+                    self.last_check = now;
+                    self.command(match self.model {
+                        Model::Scd4x => SCD4X_GET_DATA_READY_STATUS,
+                        Model::Scd30 => SCD30_GET_DATA_READY_STATUS,
+                    })?;
+                    self.state = State::CheckingReady;
+                }
+                Err(nb::Error::WouldBlock)
+            }
+            // the sensor needs some time before the response can be read
+            State::CheckingReady | State::Reading if self.is_busy(now) => {
+                Err(nb::Error::WouldBlock)
+            }
+            State::CheckingReady => {
+                let mut status = [0];
+                self.read_words(&mut status)?;
+
+                let ready = match self.model {
+                    Model::Scd4x => status[0] & 0x07ff != 0,
+                    Model::Scd30 => status[0] == 1,
+                };
+                if ready {
+                    self.command(match self.model {
+                        Model::Scd4x => SCD4X_READ_MEASUREMENT,
+                        Model::Scd30 => SCD30_READ_MEASUREMENT,
+                    })?;
+                    self.state = State::Reading;
+                } else {
+                    self.state = State::Waiting;
+                }
+                Err(nb::Error::WouldBlock)
+            }
+            State::Reading => {
+                self.state = State::Idle;
+                Ok(self.read_measurement()?)
+            }
+        }
+    }
+
+    fn read_measurement(&mut self) -> Result<Measurement, Error<E>> {
+        match self.model {
+            Model::Scd4x => {
+                let mut words = [0; 3];
+                self.read_words(&mut words)?;
+                Ok(Measurement {
+                    co2_ppm: words[0],
+                    // T = -45 + 175 * word / 2^16, RH = 100 * word / 2^16
+                    temperature: (-4500 + 17500 * words[1] as i32 / 65536) as i16,
+                    humidity: (100 * words[2] as u32 / 65536) as u16,
+                })
+            }
+            Model::Scd30 => {
+                // big-endian floats, two words each
+                let mut words = [0; 6];
+                self.read_words(&mut words)?;
+                let float =
+                    |n: usize| f32::from_bits((words[n] as u32) << 16 | words[n + 1] as u32);
+                Ok(Measurement {
+                    co2_ppm: float(0) as u16,
+                    temperature: (float(2) * 100.0) as i16,
+                    humidity: float(4) as u16,
+                })
+            }
+        }
+    }
+
+    fn timeout_ms(&self) -> u32 {
+        match self.model {
+            Model::Scd4x => SCD4X_TIMEOUT_MS,
+            Model::Scd30 => SCD30_TIMEOUT_MS,
+        }
+    }
+
+    fn address(&self) -> u8 {
+        match self.model {
+            Model::Scd4x => SCD4X_ADDRESS,
+            Model::Scd30 => SCD30_ADDRESS,
+        }
+    }
+
+    fn command(&mut self, command: u16) -> Result<(), Error<E>> {
+        let address = self.address();
+        self.sent();
+        self.i2c
+            .write(address, &command.to_be_bytes())
+            .map_err(Error::I2c)
+    }
+
+    fn command_with_arg(&mut self, command: u16, arg: u16) -> Result<(), Error<E>> {
+        let [command_high, command_low] = command.to_be_bytes();
+        let [arg_high, arg_low] = arg.to_be_bytes();
+        let buf = [
+            command_high,
+            command_low,
+            arg_high,
+            arg_low,
+            crc8(&[arg_high, arg_low]),
+        ];
+        let address = self.address();
+        self.sent();
+        self.i2c.write(address, &buf).map_err(Error::I2c)
+    }
+
+    // a command is going out now
+    fn sent(&mut self) {
+        self.last_command = self.clock.now_ms();
+        self.command_ms = match self.model {
+            Model::Scd4x => SCD4X_COMMAND_MS,
+            Model::Scd30 => SCD30_COMMAND_MS,
+        };
+    }
+
+    // whether the last command may still be under way
+    fn is_busy(&self, now: u32) -> bool {
+        now.wrapping_sub(self.last_command) < self.command_ms + self.clock.resolution_ms()
+    }
+
+    fn read_words(&mut self, words: &mut [u16]) -> Result<(), Error<E>> {
+        // enough for the longest response, 6 words
+        let mut buf = [0; 18];
+        let buf = &mut buf[..words.len() * 3];
+        let address = self.address();
+        self.i2c.read(address, buf).map_err(Error::I2c)?;
+
+        for (word, chunk) in words.iter_mut().zip(buf.chunks_exact(3)) {
+            if crc8(&chunk[..2]) != chunk[2] {
+                return Err(Error::WrongChecksum);
+            }
+            *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+        }
+        Ok(())
+    }
+
+    // only for `init`
+    fn wait(&self) {
+        while self.is_busy(self.clock.now_ms()) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::{cell::Cell, collections::VecDeque, rc::Rc, vec::Vec as StdVec};
+
+    // records the commands, and answers reads in order
+    #[derive(Default)]
+    struct I2c {
+        commands: StdVec<u16>,
+        responses: VecDeque<StdVec<u16>>,
+    }
+
+    impl Write for I2c {
+        type Error = Infallible;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Infallible> {
+            assert_eq!(address, SCD4X_ADDRESS);
+            self.commands.push(u16::from_be_bytes([bytes[0], bytes[1]]));
+            Ok(())
+        }
+    }
+
+    impl Read for I2c {
+        type Error = Infallible;
+
+        fn read(&mut self, _: u8, buf: &mut [u8]) -> Result<(), Infallible> {
+            let words = self.responses.pop_front().expect("nothing to read");
+            for (word, chunk) in words.iter().zip(buf.chunks_exact_mut(3)) {
+                chunk[..2].copy_from_slice(&word.to_be_bytes());
+                chunk[2] = crc8(&chunk[..2]);
+            }
+            Ok(())
+        }
+    }
+
+    // the system tick's 200 ms steps, moved on by hand
+    #[derive(Clone)]
+    struct Tick(Rc<Cell<u32>>);
+
+    impl Clock for Tick {
+        fn now_ms(&self) -> u32 {
+            self.0.get()
+        }
+
+        fn resolution_ms(&self) -> u32 {
+            200
+        }
+    }
+
+    #[test]
+    fn crc_matches_datasheet() {
+        assert_eq!(crc8(&[0xbe, 0xef]), 0x92);
+    }
+
+    #[test]
+    fn responses_are_read_once_the_sensor_is_done() {
+        let now = Rc::new(Cell::new(0));
+        let mut scd = Scd::scd4x(I2c::default(), Tick(now.clone()));
+        scd.i2c.responses.push_back(vec![0x0001]);
+        // 800 ppm, 25 C, 50 %
+        scd.i2c.responses.push_back(vec![800, 26215, 32768]);
+
+        // still powering up
+        scd.start_request();
+        now.set(SCD4X_POWER_UP_MS);
+        assert!(matches!(scd.poll(), Err(nb::Error::WouldBlock)));
+        assert!(scd.i2c.commands.is_empty());
+
+        now.set(SCD4X_POWER_UP_MS + 200);
+        assert!(matches!(scd.poll(), Err(nb::Error::WouldBlock)));
+        assert_eq!(scd.i2c.commands, [SCD4X_GET_DATA_READY_STATUS]);
+
+        // the next tick may come right after the command, so it takes two
+        now.set(SCD4X_POWER_UP_MS + 400);
+        assert!(matches!(scd.poll(), Err(nb::Error::WouldBlock)));
+        assert_eq!(scd.i2c.responses.len(), 2);
+        now.set(SCD4X_POWER_UP_MS + 600);
+        assert!(matches!(scd.poll(), Err(nb::Error::WouldBlock)));
+        assert_eq!(scd.i2c.commands[1], SCD4X_READ_MEASUREMENT);
+
+        assert!(matches!(scd.poll(), Err(nb::Error::WouldBlock)));
+        now.set(SCD4X_POWER_UP_MS + 1000);
+        assert_eq!(
+            scd.poll().unwrap(),
+            Measurement {
+                co2_ppm: 800,
+                temperature: 2500,
+                humidity: 50,
+            }
+        );
+    }
+}
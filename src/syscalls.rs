@@ -1,5 +1,58 @@
+#[cfg(not(any(feature = "scd4x", feature = "scd30")))]
+use core::convert::Infallible;
 use cortex_m::interrupt::free;
-use heapless::{ArrayLength, Vec};
+#[cfg(not(any(feature = "scd4x", feature = "scd30")))]
+use embedded_hal::serial::{Read, Write};
+
+use clima_sensors::clock::Clock;
+
+/// The system tick, as a clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u32 {
+        get_current_ticks().wrapping_mul(1000 / crate::TICKS_PER_SECOND)
+    }
+
+    fn resolution_ms(&self) -> u32 {
+        1000 / crate::TICKS_PER_SECOND
+    }
+}
+
+#[cfg(not(any(feature = "scd4x", feature = "scd30")))]
+/// A UART which is read through `UART_BUFFER`, since the interrupt takes everything it receives.
+pub struct BufferedUart<W> {
+    tx: W,
+}
+
+#[cfg(not(any(feature = "scd4x", feature = "scd30")))]
+impl<W> BufferedUart<W> {
+    pub fn new(tx: W) -> Self {
+        Self { tx }
+    }
+}
+
+#[cfg(not(any(feature = "scd4x", feature = "scd30")))]
+impl<W> Read<u8> for BufferedUart<W> {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        uart_buffer_pop().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+#[cfg(not(any(feature = "scd4x", feature = "scd30")))]
+impl<W: Write<u8>> Write<u8> for BufferedUart<W> {
+    type Error = W::Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), W::Error> {
+        self.tx.write(word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), W::Error> {
+        self.tx.flush()
+    }
+}
 
 pub fn get_current_ticks() -> u32 {
     free(|cs| {
@@ -11,16 +64,14 @@ pub fn get_current_ticks() -> u32 {
 pub fn uart_buffer_push(c: u8) {
     free(|cs| {
         let mut buffer = crate::UART_BUFFER.borrow(cs).borrow_mut();
-        buffer.push((c & 0xff) as u8);
+        buffer.push(c);
     });
 }
 
-pub fn uart_buffer_pop<'t, N>() -> Vec<u8, N>
-where
-    N: ArrayLength<u8>,
-{
+#[cfg(not(any(feature = "scd4x", feature = "scd30")))]
+pub fn uart_buffer_pop() -> Option<u8> {
     free(|cs| {
         let mut input_buffer = crate::UART_BUFFER.borrow(cs).borrow_mut();
-        input_buffer.by_ref().take(9).collect()
+        input_buffer.pop()
     })
 }
@@ -7,6 +7,8 @@ use ssd1306::{mode::GraphicsMode, prelude::*};
 use tinybmp::Bmp;
 use ufmt::uwrite;
 
+use clima_sensors::co2::Condition;
+
 use crate::SystemData;
 
 const NUM_LOG_LINES: usize = 4;
@@ -54,6 +56,10 @@ where
             let t_int = t / 100;
             let t_dec = t % 100;
             uwrite!(&mut text, "{}.{}C", t_int, t_dec).unwrap();
+            // the CO2 sensor doesn't agree
+            if system_data.sensors.temperature_mismatch() {
+                uwrite!(&mut text, "?").unwrap();
+            }
         }
 
         egtext!(
@@ -64,7 +70,8 @@ where
         .draw(&mut self.display)
         .unwrap();
 
-        text.clear();
+        // not `clear`: heapless 0.5's `truncate` indexes past the end of the buffer
+        text = String::new();
 
         if system_data.errors.humidity {
             uwrite!(&mut text, "ERR").unwrap();
@@ -81,13 +88,19 @@ where
         .draw(&mut self.display)
         .unwrap();
 
-        text.clear();
+        text = String::new();
 
         if system_data.errors.co2 {
             uwrite!(&mut text, "ERR").unwrap();
         } else {
             let c = system_data.sensors.avgs.co2;
-            uwrite!(&mut text, "{}ppm", c).unwrap();
+            if system_data.sensors.co2_warming_up {
+                uwrite!(&mut text, "WARMING").unwrap();
+            } else if system_data.sensors.co2_condition() == Some(Condition::Degraded) {
+                uwrite!(&mut text, "{}ppm?", c).unwrap();
+            } else {
+                uwrite!(&mut text, "{}ppm", c).unwrap();
+            }
         }
 
         // match system_data.sensors.co2.recent() {
@@ -107,11 +120,46 @@ where
         .draw(&mut self.display)
         .unwrap();
 
-        if system_data.ticks_since_last_tx < 10 {
+        if system_data.errors.radio {
+            egtext!(
+                text = "ERR",
+                top_left = (95, 18),
+                style = text_style!(font = ProFont12Point, text_color = BinaryColor::On)
+            )
+            .draw(&mut self.display)
+            .unwrap();
+        } else if system_data.ticks_since_last_tx < 10 {
             self.rf_icon.draw(&mut self.display).unwrap();
         }
     }
 
+    /// `waiting` is for when there's no airtime left.
+    pub fn draw_range_test(&mut self, frames_sent: u32, waiting: bool) {
+        let mut text: String<U16> = String::new();
+        uwrite!(&mut text, "TX {}", frames_sent).unwrap();
+
+        egtext!(
+            text = &text,
+            top_left = (0, 0),
+            style = text_style!(font = ProFont12Point, text_color = BinaryColor::On)
+        )
+        .draw(&mut self.display)
+        .unwrap();
+
+        let label = if waiting {
+            "Range test (WAIT)"
+        } else {
+            "Range test"
+        };
+        egtext!(
+            text = label,
+            top_left = (0, 22),
+            style = text_style!(font = ProFont9Point, text_color = BinaryColor::On)
+        )
+        .draw(&mut self.display)
+        .unwrap();
+    }
+
     pub fn log_to_screen(&mut self, text: &str) {
         self.display.clear();
 
@@ -0,0 +1,69 @@
+// Encoding of readings on the wire: fixed-size, always little-endian.
+
+use core::marker::PhantomData;
+use heapless::{consts::*, Vec};
+
+/// Largest encoded reading, matches the capacity of the `Vec` returned by `to_vec`
+pub const MAX_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    TooShort,
+}
+
+pub trait Encode {
+    const SIZE: usize;
+
+    /// `buf` is exactly `SIZE` bytes long
+    fn encode(&self, buf: &mut [u8]);
+}
+
+pub trait Decode: Sized {
+    fn decode(buf: &[u8]) -> Result<Self, Error>;
+}
+
+macro_rules! impl_wire {
+    ($($t:ty),+) => {
+        $(
+            impl Encode for $t {
+                const SIZE: usize = core::mem::size_of::<$t>();
+
+                fn encode(&self, buf: &mut [u8]) {
+                    buf.copy_from_slice(&self.to_le_bytes());
+                }
+            }
+
+            impl Decode for $t {
+                fn decode(buf: &[u8]) -> Result<Self, Error> {
+                    const SIZE: usize = core::mem::size_of::<$t>();
+
+                    if buf.len() < SIZE {
+                        return Err(Error::TooShort);
+                    }
+                    let mut bytes = [0u8; SIZE];
+                    bytes.copy_from_slice(&buf[..SIZE]);
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )+
+    };
+}
+
+impl_wire!(u8, i8, u16, i16, u32, i32);
+
+struct Fits<T>(PhantomData<T>);
+
+impl<T: Encode> Fits<T> {
+    // evaluated at compile time, for every `T` passed to `to_vec`
+    const OK: () = assert!(T::SIZE <= MAX_SIZE, "type is too big to be sent");
+}
+
+pub fn to_vec<T: Encode>(value: &T) -> Vec<u8, U4> {
+    let () = Fits::<T>::OK;
+
+    let mut buf = Vec::new();
+    // can't fail, `Fits` makes sure the value isn't bigger than the buffer
+    buf.resize_default(T::SIZE).unwrap();
+    value.encode(&mut buf);
+    buf
+}